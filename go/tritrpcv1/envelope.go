@@ -10,15 +10,19 @@ var MAGIC_B2 = []byte{0xF3, 0x2A}
 var SCHEMA_ID_32 = []byte{0xb2, 0xab, 0x81, 0x45, 0x88, 0xf9, 0x9c, 0x87, 0x5d, 0x37, 0xbb, 0x75, 0x46, 0xd0, 0xdf, 0x43, 0x69, 0xc2, 0x8b, 0xc5, 0xf6, 0x0c, 0xe3, 0x8a, 0x66, 0x07, 0xda, 0xc4, 0x68, 0x03, 0x43, 0x52}
 var CONTEXT_ID_32 = []byte{0xe6, 0x57, 0x2c, 0x0e, 0x61, 0x8f, 0x18, 0xd5, 0x72, 0xd4, 0xc2, 0x96, 0x9d, 0xb4, 0x90, 0x96, 0x59, 0xf0, 0x9e, 0xae, 0xf3, 0x2e, 0xc6, 0x6f, 0xbb, 0x80, 0x4b, 0xad, 0x9d, 0x89, 0xaa, 0xcd}
 
-func flagsTrits(aead bool, compress bool) []byte {
-	var a, c byte
+// Flags trits are [aead, compress, aux]; 2 = set, 0 = clear.
+func flagsTrits(aead bool, compress bool, aux bool) []byte {
+	var a, c, x byte
 	if aead {
 		a = 2
 	}
 	if compress {
 		c = 2
 	}
-	return []byte{a, c, 0}
+	if aux {
+		x = 2
+	}
+	return []byte{a, c, x}
 }
 
 func lenPrefix(b []byte) []byte {
@@ -38,7 +42,7 @@ func BuildEnvelope(service, method string, payload []byte, aux []byte, aeadTag [
 	out = append(out, lenPrefix(mode)...)
 	out = append(out, mode...)
 
-	flags := TritPack243(flagsTrits(aeadOn, compress))
+	flags := TritPack243(flagsTrits(aeadOn, compress, aux != nil))
 	out = append(out, lenPrefix(flags)...)
 	out = append(out, flags...)
 
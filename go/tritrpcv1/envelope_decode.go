@@ -78,36 +78,33 @@ func DecodeEnvelope(frame []byte) (*Envelope, error) {
 	trits, _ := TritUnpack243(flags)
 	aeadOn := len(trits) > 0 && trits[0] == 2
 	compress := len(trits) > 1 && trits[1] == 2
+	auxOn := len(trits) > 2 && trits[2] == 2
 
 	var aux []byte
 	var tag []byte
 	tagStart := -1
 
-	if off < len(frame) {
-		if aeadOn {
-			field, _, start, err := readField()
-			if err != nil {
-				return nil, err
-			}
-			if off < len(frame) {
-				tagField, _, tagFieldStart, err := readField()
-				if err != nil {
-					return nil, err
-				}
-				aux = append([]byte{}, field...)
-				tag = append([]byte{}, tagField...)
-				tagStart = tagFieldStart
-			} else {
-				tag = append([]byte{}, field...)
-				tagStart = start
-			}
-		} else {
-			field, _, _, err := readField()
-			if err != nil {
-				return nil, err
-			}
-			aux = append([]byte{}, field...)
+	// The flags declare the trailing fields; nothing is inferred from what remains.
+	if auxOn {
+		if off == len(frame) {
+			return nil, errors.New("missing aux field declared by flags")
 		}
+		field, _, _, err := readField()
+		if err != nil {
+			return nil, err
+		}
+		aux = append([]byte{}, field...)
+	}
+	if aeadOn {
+		if off == len(frame) {
+			return nil, errors.New("missing tag field declared by flags")
+		}
+		field, _, start, err := readField()
+		if err != nil {
+			return nil, err
+		}
+		tag = append([]byte{}, field...)
+		tagStart = start
 	}
 
 	if off != len(frame) {
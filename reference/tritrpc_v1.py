@@ -264,8 +264,8 @@ def enc_HGRequest_GetSubgraph(vid:str, k:int)->bytes:
 MAGIC_B2 = bytes.fromhex("f3 2a")  # Appendix A normative example; treat as fixed B2 canonical for 9-trit constant
 VER_TRITS   = [1]  # v1
 MODE_TRITS  = [0]  # B2
-def flags_trits(aead:bool, compress:bool=False)->List[int]:
-    return [2 if aead else 0, 2 if compress else 0, 0]  # interpret as bits (2==true)
+def flags_trits(aead:bool, compress:bool=False, aux:bool=False)->List[int]:
+    return [2 if aead else 0, 2 if compress else 0, 2 if aux else 0]  # interpret as bits (2==true)
 
 # IDs (32 bytes each). For vectors, fix them.
 SCHEMA_ID = hashlib.sha3_256(b"HG_AVRO_v1").digest()
@@ -282,7 +282,7 @@ def build_envelope(service:str, method:str, payload:bytes, aux:Optional[bytes],
     out += len_prefix(ver_b) + ver_b
     mode_b = tritpack243_pack(MODE_TRITS)
     out += len_prefix(mode_b) + mode_b
-    flags_b = tritpack243_pack(flags_trits(aead_on, compress))
+    flags_b = tritpack243_pack(flags_trits(aead_on, compress, aux is not None))
     out += len_prefix(flags_b) + flags_b
     # IDs
     out += len_prefix(SCHEMA_ID) + SCHEMA_ID
@@ -338,6 +338,10 @@ def kafka_record(value_bytes: bytes, key_fields: tuple[str,str,str,str]) -> dict
 
 
 
+# ===== AUX bundle: map<string, bytes> in name order ("trace", "kid", ...) =====
+def enc_AuxBundle(entries:dict)->bytes:
+    return enc_map(dict(sorted(entries.items())), enc_bytes)
+
 # ===== Additional encoders: Trace, HGResponse, HGStream* (toy Path-A subset) =====
 def enc_Trace(trace_id:str, span_id:str, parent:Optional[str])->bytes:
     out = bytearray()
@@ -1,13 +1,12 @@
 use std::env;
 use std::fs;
 use std::process::exit;
-use tritrpc_v1::{avroenc, envelope};
+use tritrpc_v1::{avroenc_json, envelope, tritrpc_v1_tests};
 
 fn hex_to_bytes(s: &str) -> Vec<u8> {
     let s = s.trim();
     let mut out = Vec::new();
-    let mut it = s.as_bytes().chunks(2);
-    for ch in it {
+    for ch in s.as_bytes().chunks(2) {
         let hh = std::str::from_utf8(ch).unwrap();
         out.push(u8::from_str_radix(hh, 16).unwrap());
     }
@@ -71,12 +70,12 @@ fn main() {
             let js = fs::read_to_string(&jsonp).expect("read json");
             let v: serde_json::Value = serde_json::from_str(&js).expect("json");
             let payload = if m.ends_with(".REQ") || m.ends_with(".Req") || m.ends_with(".Request") {
-                avroenc::enc_HGRequest(&v)
+                avroenc_json::enc_HGRequest(&v)
             } else if m.ends_with(".RSP") || m.ends_with(".Resp") || m.ends_with(".Response") {
-                avroenc::enc_HGResponse_json(&v)
+                avroenc_json::enc_HGResponse_json(&v)
             } else {
                 // raw: assume request
-                avroenc::enc_HGRequest(&v)
+                avroenc_json::enc_HGRequest(&v)
             };
             let keyb = hex_to_bytes(&key_hex);
             let nonceb = hex_to_bytes(&nonce_hex);
@@ -1,65 +1,484 @@
+pub mod error {
+    use std::fmt;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Error {
+        TruncatedTail {
+            offset: usize,
+        },
+        InvalidPackByte {
+            byte: u8,
+            offset: usize,
+        },
+        TlebEof {
+            offset: usize,
+        },
+        FieldOverrun {
+            field: &'static str,
+            offset: usize,
+        },
+        NotUtf8 {
+            field: &'static str,
+            offset: usize,
+        },
+        TrailingBytes {
+            what: &'static str,
+            offset: usize,
+        },
+        VarintOverflow {
+            offset: usize,
+        },
+        Eof {
+            what: &'static str,
+            offset: usize,
+        },
+        NegativeLength {
+            field: &'static str,
+            offset: usize,
+        },
+        InvalidUnionIndex {
+            field: &'static str,
+            index: i64,
+            offset: usize,
+        },
+        NonZeroTerminator {
+            field: &'static str,
+            offset: usize,
+        },
+        MissingField {
+            field: &'static str,
+        },
+        Unsupported {
+            what: &'static str,
+        },
+        InvalidTrit {
+            value: u8,
+            index: usize,
+        },
+        InvalidTritValue {
+            value: u8,
+        },
+        TailValueOutOfRange {
+            value: u8,
+            trits: usize,
+            offset: usize,
+        },
+        TailNotFinal {
+            offset: usize,
+        },
+        TlebOverflow {
+            offset: usize,
+        },
+        NonCanonicalTleb {
+            offset: usize,
+            reason: &'static str,
+        },
+        FrameTooLarge {
+            len: u64,
+            max: usize,
+        },
+        BadMagic {
+            offset: usize,
+        },
+        InvalidFlags {
+            offset: usize,
+        },
+        AuthFailed,
+        SuiteLength {
+            suite: &'static str,
+            what: &'static str,
+            len: usize,
+            expected: usize,
+        },
+        NonceExhausted {
+            chunk_index: u64,
+        },
+        Replayed,
+        UnknownKeyId {
+            key_id: String,
+        },
+        KeyNotActive {
+            key_id: String,
+        },
+        NoActiveKey,
+        DuplicateKeyId {
+            key_id: String,
+        },
+        UnexpectedMessage {
+            method: String,
+        },
+        NoCommonParameter {
+            what: &'static str,
+        },
+        InvalidChoice {
+            what: &'static str,
+        },
+        HandshakeRejected {
+            code: i32,
+            message: String,
+        },
+        HandshakeState {
+            what: &'static str,
+        },
+        BadKeyShare {
+            reason: &'static str,
+        },
+        BadTraceContext {
+            what: &'static str,
+        },
+        PoEMismatch {
+            field: &'static str,
+        },
+        BadSignature,
+        NonFiniteNumber,
+        Json {
+            message: String,
+        },
+        BadIdLength {
+            field: &'static str,
+            len: usize,
+            offset: usize,
+        },
+        UnsupportedVersion {
+            offset: usize,
+        },
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::TruncatedTail { offset } => {
+                    write!(f, "truncated tail marker at offset {}", offset)
+                }
+                Error::InvalidPackByte { byte, offset } => {
+                    write!(f, "invalid TritPack243 byte {} at offset {}", byte, offset)
+                }
+                Error::TlebEof { offset } => {
+                    write!(f, "EOF in TLEB3 starting at offset {}", offset)
+                }
+                Error::FieldOverrun { field, offset } => {
+                    write!(f, "{} at offset {} exceeds buffer", field, offset)
+                }
+                Error::NotUtf8 { field, offset } => {
+                    write!(f, "{} at offset {} is not utf8", field, offset)
+                }
+                Error::TrailingBytes { what, offset } => {
+                    write!(f, "extra bytes after {} at offset {}", what, offset)
+                }
+                Error::VarintOverflow { offset } => {
+                    write!(f, "varint overflow at offset {}", offset)
+                }
+                Error::Eof { what, offset } => write!(f, "EOF in {} at offset {}", what, offset),
+                Error::NegativeLength { field, offset } => {
+                    write!(f, "negative {} length at offset {}", field, offset)
+                }
+                Error::InvalidUnionIndex {
+                    field,
+                    index,
+                    offset,
+                } => write!(
+                    f,
+                    "invalid union index {} for {} at offset {}",
+                    index, field, offset
+                ),
+                Error::NonZeroTerminator { field, offset } => {
+                    write!(f, "non-zero {} terminator at offset {}", field, offset)
+                }
+                Error::MissingField { field } => write!(f, "missing {}", field),
+                Error::Unsupported { what } => write!(f, "unsupported {}", what),
+                Error::InvalidTrit { value, index } => {
+                    write!(f, "invalid trit {} at index {}", value, index)
+                }
+                Error::InvalidTritValue { value } => write!(f, "invalid trit {}", value),
+                Error::TailValueOutOfRange {
+                    value,
+                    trits,
+                    offset,
+                } => write!(
+                    f,
+                    "tail value {} at offset {} does not fit in {} trits",
+                    value, offset, trits
+                ),
+                Error::TailNotFinal { offset } => {
+                    write!(f, "tail marker at offset {} is not the final group", offset)
+                }
+                Error::TlebOverflow { offset } => {
+                    write!(f, "TLEB3 at offset {} overflows u64", offset)
+                }
+                Error::NonCanonicalTleb { offset, reason } => {
+                    write!(f, "non-canonical TLEB3 at offset {}: {}", offset, reason)
+                }
+                Error::FrameTooLarge { len, max } => {
+                    write!(f, "frame length {} exceeds limit {}", len, max)
+                }
+                Error::BadMagic { offset } => write!(f, "bad magic at offset {}", offset),
+                Error::AuthFailed => write!(f, "AEAD tag verification failed"),
+                Error::SuiteLength {
+                    suite,
+                    what,
+                    len,
+                    expected,
+                } => write!(
+                    f,
+                    "{} {} is {} bytes, expected {}",
+                    suite, what, len, expected
+                ),
+                Error::Replayed => write!(f, "frame nonce already accepted (replay)"),
+                Error::UnknownKeyId { key_id } => write!(f, "unknown key id {:?}", key_id),
+                Error::KeyNotActive { key_id } => {
+                    write!(f, "key {:?} is not active at this time", key_id)
+                }
+                Error::NoActiveKey => write!(f, "no active key in keyring"),
+                Error::DuplicateKeyId { key_id } => {
+                    write!(f, "key id {:?} already in keyring", key_id)
+                }
+                Error::UnexpectedMessage { method } => {
+                    write!(f, "unexpected control message {}", method)
+                }
+                Error::NoCommonParameter { what } => write!(f, "no common {} with peer", what),
+                Error::InvalidChoice { what } => {
+                    write!(f, "peer chose a {} that was not offered", what)
+                }
+                Error::HandshakeRejected { code, message } => {
+                    write!(f, "handshake rejected by peer (code {}): {}", code, message)
+                }
+                Error::HandshakeState { what } => {
+                    write!(f, "{} called out of order in handshake", what)
+                }
+                Error::BadKeyShare { reason } => write!(f, "bad X25519 key share: {}", reason),
+                Error::BadTraceContext { what } => write!(f, "invalid trace context {}", what),
+                Error::PoEMismatch { field } => {
+                    write!(f, "PoE {} does not match the frame", field)
+                }
+                Error::BadSignature => write!(f, "signature verification failed"),
+                Error::NonFiniteNumber => write!(f, "non-finite number in canonical JSON"),
+                Error::Json { message } => write!(f, "invalid JSON: {}", message),
+                Error::NonceExhausted { chunk_index } => {
+                    write!(f, "stream nonce counter exhausted at chunk {}", chunk_index)
+                }
+                Error::InvalidFlags { offset } => {
+                    write!(f, "flags at offset {} are not three 0/2 trits", offset)
+                }
+                Error::BadIdLength { field, len, offset } => write!(
+                    f,
+                    "{} id at offset {} is {} bytes, expected 32",
+                    field, offset, len
+                ),
+                Error::UnsupportedVersion { offset } => {
+                    write!(f, "unsupported version at offset {}", offset)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+pub use error::Error;
+
 pub mod tritpack243 {
-    pub fn pack(trits: &[u8]) -> Vec<u8> {
-        let mut out: Vec<u8> = Vec::new();
-        let mut i: usize = 0;
-        while i + 5 <= trits.len() {
-            let mut val: u32 = 0;
-            for &t in &trits[i..i + 5] {
-                assert!(t <= 2, "invalid trit");
-                val = val * 3 + t as u32;
+    use super::Error;
+    use std::ops::Deref;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+    pub struct Trit(u8);
+
+    impl Trit {
+        pub const ZERO: Trit = Trit(0);
+        pub const ONE: Trit = Trit(1);
+        pub const TWO: Trit = Trit(2);
+
+        pub fn new(value: u8) -> Option<Trit> {
+            if value <= 2 {
+                Some(Trit(value))
+            } else {
+                None
             }
+        }
+
+        pub fn value(self) -> u8 {
+            self.0
+        }
+    }
+
+    impl TryFrom<u8> for Trit {
+        type Error = Error;
+
+        fn try_from(value: u8) -> Result<Trit, Error> {
+            Trit::new(value).ok_or(Error::InvalidTritValue { value })
+        }
+    }
+
+    impl From<Trit> for u8 {
+        fn from(t: Trit) -> u8 {
+            t.0
+        }
+    }
+
+    pub type TritSlice = [Trit];
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+    pub struct TritVec(Vec<Trit>);
+
+    impl TritVec {
+        pub fn new() -> TritVec {
+            TritVec(Vec::new())
+        }
+
+        // Validate raw trit values (0..=2), reporting the index of the first bad one.
+        pub fn from_raw(raw: &[u8]) -> Result<TritVec, Error> {
+            raw.iter()
+                .enumerate()
+                .map(|(index, &value)| Trit::new(value).ok_or(Error::InvalidTrit { value, index }))
+                .collect::<Result<Vec<_>, _>>()
+                .map(TritVec)
+        }
+
+        pub fn unpack(bytes: &[u8]) -> Result<TritVec, Error> {
+            // unpack only ever yields values 0..=2
+            Ok(TritVec(unpack(bytes)?.into_iter().map(Trit).collect()))
+        }
+
+        pub fn push(&mut self, t: Trit) {
+            self.0.push(t);
+        }
+
+        pub fn as_slice(&self) -> &TritSlice {
+            &self.0
+        }
+
+        pub fn to_raw(&self) -> Vec<u8> {
+            self.0.iter().map(|t| t.0).collect()
+        }
+
+        pub fn pack(&self) -> Vec<u8> {
+            pack_trits(&self.0)
+        }
+    }
+
+    impl Deref for TritVec {
+        type Target = TritSlice;
+
+        fn deref(&self) -> &TritSlice {
+            &self.0
+        }
+    }
+
+    impl From<Vec<Trit>> for TritVec {
+        fn from(v: Vec<Trit>) -> TritVec {
+            TritVec(v)
+        }
+    }
+
+    impl FromIterator<Trit> for TritVec {
+        fn from_iter<I: IntoIterator<Item = Trit>>(iter: I) -> TritVec {
+            TritVec(iter.into_iter().collect())
+        }
+    }
+
+    pub fn pack_trits(trits: &TritSlice) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(trits.len() / 5 + 2);
+        let mut chunks = trits.chunks_exact(5);
+        for group in &mut chunks {
+            let val = group.iter().fold(0u32, |acc, t| acc * 3 + t.0 as u32);
             out.push(val as u8);
-            i += 5;
         }
-        let k = trits.len() - i;
-        if k > 0 {
-            out.push(243 + (k as u8 - 1));
-            let mut val: u32 = 0;
-            for &t in &trits[i..] {
-                val = val * 3 + t as u32;
-            }
+        let tail = chunks.remainder();
+        if !tail.is_empty() {
+            out.push(243 + (tail.len() as u8 - 1));
+            let val = tail.iter().fold(0u32, |acc, t| acc * 3 + t.0 as u32);
             out.push(val as u8);
         }
         out
     }
 
-    pub fn unpack(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    pub fn try_pack(trits: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(TritVec::from_raw(trits)?.pack())
+    }
+
+    // Panics on values > 2; use try_pack for untrusted input.
+    pub fn pack(trits: &[u8]) -> Vec<u8> {
+        try_pack(trits).expect("invalid trit")
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum UnpackMode {
+        // Reject anything pack() could not have produced.
+        Strict,
+        // Accept mid-stream tail groups and reduce oversized tail values modulo 3^k.
+        Lenient,
+    }
+
+    // Unpack the single group (full byte or tail marker + value) starting at `off`.
+    pub fn unpack_group(
+        bytes: &[u8],
+        off: usize,
+        mode: UnpackMode,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        let b = *bytes.get(off).ok_or(Error::Eof {
+            what: "TritPack243 group",
+            offset: off,
+        })?;
+        if b <= 242 {
+            let mut val = b as u32;
+            let mut group = vec![0u8; 5];
+            for j in (0..5).rev() {
+                group[j] = (val % 3) as u8;
+                val /= 3;
+            }
+            Ok((group, off + 1))
+        } else if (243..=246).contains(&b) {
+            if off + 1 >= bytes.len() {
+                return Err(Error::TruncatedTail { offset: off });
+            }
+            let k = (b - 243 + 1) as usize;
+            let mut val = bytes[off + 1] as u32;
+            if mode == UnpackMode::Strict && val >= 3u32.pow(k as u32) {
+                return Err(Error::TailValueOutOfRange {
+                    value: bytes[off + 1],
+                    trits: k,
+                    offset: off + 1,
+                });
+            }
+            let mut group = vec![0u8; k];
+            for j in (0..k).rev() {
+                group[j] = (val % 3) as u8;
+                val /= 3;
+            }
+            Ok((group, off + 2))
+        } else {
+            Err(Error::InvalidPackByte {
+                byte: b,
+                offset: off,
+            })
+        }
+    }
+
+    pub fn unpack_with(bytes: &[u8], mode: UnpackMode) -> Result<Vec<u8>, Error> {
         let mut trits: Vec<u8> = Vec::new();
         let mut i: usize = 0;
         while i < bytes.len() {
-            let b = bytes[i];
-            i += 1;
-            if b <= 242 {
-                let mut val = b as u32;
-                let mut group = [0u8; 5];
-                for j in (0..5).rev() {
-                    group[j] = (val % 3) as u8;
-                    val /= 3;
-                }
-                trits.extend_from_slice(&group);
-            } else if (243..=246).contains(&b) {
-                if i >= bytes.len() {
-                    return Err("truncated tail marker".into());
-                }
-                let k = (b - 243 + 1) as usize;
-                let mut val = bytes[i] as u32;
-                i += 1;
-                let mut group = vec![0u8; k];
-                for j in (0..k).rev() {
-                    group[j] = (val % 3) as u8;
-                    val /= 3;
-                }
-                trits.extend(group);
-            } else {
-                return Err("invalid byte 247..255 in canonical stream".into());
+            let (group, next) = unpack_group(bytes, i, mode)?;
+            if mode == UnpackMode::Strict && group.len() < 5 && next < bytes.len() {
+                return Err(Error::TailNotFinal { offset: i });
             }
+            trits.extend(group);
+            i = next;
         }
         Ok(trits)
     }
+
+    pub fn unpack(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        unpack_with(bytes, UnpackMode::Strict)
+    }
+
+    pub fn unpack_lenient(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        unpack_with(bytes, UnpackMode::Lenient)
+    }
 }
 
 pub mod tleb3 {
-    use super::tritpack243;
+    use super::{tritpack243, Error};
     pub fn encode_len(mut n: u64) -> Vec<u8> {
         let mut digits: Vec<u8> = Vec::new();
         if n == 0 {
@@ -76,248 +495,2820 @@ pub mod tleb3 {
             let p1 = d / 3;
             let p0 = d % 3;
             trits.push(c);
-            trits.push(*p1);
-            trits.push(*p0);
+            trits.push(p1);
+            trits.push(p0);
+        }
+        tritpack243::pack(&trits)
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Progress<T> {
+        // At least this many more bytes are required before anything completes.
+        NeedMore(usize),
+        Complete(T),
+    }
+
+    // Push-based TLEB3 decoder for lengths arriving a byte at a time. Buffers at most
+    // one encoded length and applies the same canonical checks as decode_len.
+    #[derive(Debug, Clone, Default)]
+    pub struct LenDecoder {
+        buf: Vec<u8>,
+    }
+
+    impl LenDecoder {
+        pub fn new() -> LenDecoder {
+            LenDecoder::default()
+        }
+
+        pub fn push(&mut self, byte: u8) -> Result<Progress<u64>, Error> {
+            self.buf.push(byte);
+            match decode_len(&self.buf, 0) {
+                Ok((val, _)) => {
+                    self.buf.clear();
+                    Ok(Progress::Complete(val))
+                }
+                Err(Error::TlebEof { .. }) | Err(Error::TruncatedTail { .. }) => {
+                    Ok(Progress::NeedMore(1))
+                }
+                Err(e) => {
+                    self.buf.clear();
+                    Err(e)
+                }
+            }
+        }
+
+        pub fn is_idle(&self) -> bool {
+            self.buf.is_empty()
+        }
+    }
+
+    // u64::MAX needs 21 base-9 digits.
+    pub const MAX_DIGITS: usize = 21;
+
+    pub fn decode_len(bytes: &[u8], offset: usize) -> Result<(u64, usize), Error> {
+        let mut trits: Vec<u8> = Vec::new();
+        let mut off = offset;
+        loop {
+            if off >= bytes.len() {
+                return Err(Error::TlebEof { offset });
+            }
+            let (ts, next) =
+                tritpack243::unpack_group(bytes, off, tritpack243::UnpackMode::Strict)?;
+            trits.extend(ts);
+            off = next;
+            let mut val: u64 = 0;
+            for j in 0..(trits.len() / 3) {
+                if j >= MAX_DIGITS {
+                    return Err(Error::TlebOverflow { offset });
+                }
+                let c = trits[3 * j];
+                let p1 = trits[3 * j + 1] as u64;
+                let p0 = trits[3 * j + 2] as u64;
+                let digit = p1 * 3 + p0;
+                val = digit
+                    .checked_mul(9u64.pow(j as u32))
+                    .and_then(|d| val.checked_add(d))
+                    .ok_or(Error::TlebOverflow { offset })?;
+                match c {
+                    0 => {
+                        if j > 0 && digit == 0 {
+                            return Err(Error::NonCanonicalTleb {
+                                offset,
+                                reason: "redundant high zero digit",
+                            });
+                        }
+                        // The consumed bytes must be exactly what encode_len emits: no
+                        // trailing trits (zero or not) and no tail group before the end.
+                        if tritpack243::pack(&trits[..(j + 1) * 3]) != bytes[offset..off] {
+                            return Err(Error::NonCanonicalTleb {
+                                offset,
+                                reason: "trailing trits after final digit",
+                            });
+                        }
+                        return Ok((val, off));
+                    }
+                    2 => {}
+                    _ => {
+                        return Err(Error::NonCanonicalTleb {
+                            offset,
+                            reason: "continuation trit must be 0 or 2",
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub mod aead {
+    use super::Error;
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use subtle::ConstantTimeEq;
+
+    // Suite identifiers as advertised in Hello.aead_suites and picked in Choose.aead_suite.
+    pub const XCHACHA20_POLY1305: &str = "XCHACHA20-POLY1305";
+    pub const CHACHA20_POLY1305: &str = "CHACHA20-POLY1305";
+    pub const AES_256_GCM_SIV: &str = "AES-256-GCM-SIV";
+    // Integrity only, for peers without an AEAD. Never chosen implicitly: it is not in
+    // SUITE_IDS and is only used when both sides name it.
+    pub const BLAKE2B_MAC: &str = "BLAKE2b-MAC";
+
+    // An integrity suite for the envelope tag field: a tag over an empty plaintext
+    // with the frame bytes as AAD.
+    pub trait AeadSuite {
+        fn id(&self) -> &'static str;
+
+        fn key_len(&self) -> usize {
+            32
+        }
+
+        fn nonce_len(&self) -> usize;
+
+        fn tag_len(&self) -> usize {
+            16
+        }
+
+        fn is_aead(&self) -> bool {
+            true
+        }
+
+        // Key and nonce lengths have been checked by the caller.
+        fn raw_tag(&self, key: &[u8], nonce: &[u8], aad: &[u8]) -> Vec<u8>;
+
+        fn check_params(&self, key: &[u8], nonce: &[u8]) -> Result<(), Error> {
+            check_len(self.id(), "key", key.len(), self.key_len())?;
+            check_len(self.id(), "nonce", nonce.len(), self.nonce_len())
+        }
+
+        fn compute_tag(&self, key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+            self.check_params(key, nonce)?;
+            Ok(self.raw_tag(key, nonce, aad))
+        }
+
+        fn verify_tag(
+            &self,
+            key: &[u8],
+            nonce: &[u8],
+            aad: &[u8],
+            tag: &[u8],
+        ) -> Result<(), Error> {
+            check_len(self.id(), "tag", tag.len(), self.tag_len())?;
+            let expected = self.compute_tag(key, nonce, aad)?;
+            if !bool::from(expected.ct_eq(tag)) {
+                return Err(Error::AuthFailed);
+            }
+            Ok(())
+        }
+    }
+
+    fn check_len(
+        suite: &'static str,
+        what: &'static str,
+        len: usize,
+        expected: usize,
+    ) -> Result<(), Error> {
+        if len != expected {
+            return Err(Error::SuiteLength {
+                suite,
+                what,
+                len,
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    fn empty_tag<A: Aead + KeyInit>(key: &[u8], nonce: &[u8], aad: &[u8]) -> Vec<u8> {
+        let aead = A::new_from_slice(key).expect("key length checked");
+        let mut ct = aead
+            .encrypt(nonce.into(), Payload { msg: b"", aad })
+            .expect("encrypt");
+        ct.split_off(ct.len() - 16)
+    }
+
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct XChaCha20Poly1305Suite;
+
+    impl AeadSuite for XChaCha20Poly1305Suite {
+        fn id(&self) -> &'static str {
+            XCHACHA20_POLY1305
+        }
+
+        fn nonce_len(&self) -> usize {
+            24
+        }
+
+        fn raw_tag(&self, key: &[u8], nonce: &[u8], aad: &[u8]) -> Vec<u8> {
+            empty_tag::<chacha20poly1305::XChaCha20Poly1305>(key, nonce, aad)
+        }
+    }
+
+    // IETF ChaCha20-Poly1305 with 12-byte nonces.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct ChaCha20Poly1305Suite;
+
+    impl AeadSuite for ChaCha20Poly1305Suite {
+        fn id(&self) -> &'static str {
+            CHACHA20_POLY1305
+        }
+
+        fn nonce_len(&self) -> usize {
+            12
+        }
+
+        fn raw_tag(&self, key: &[u8], nonce: &[u8], aad: &[u8]) -> Vec<u8> {
+            empty_tag::<chacha20poly1305::ChaCha20Poly1305>(key, nonce, aad)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Aes256GcmSivSuite;
+
+    impl AeadSuite for Aes256GcmSivSuite {
+        fn id(&self) -> &'static str {
+            AES_256_GCM_SIV
+        }
+
+        fn nonce_len(&self) -> usize {
+            12
+        }
+
+        fn raw_tag(&self, key: &[u8], nonce: &[u8], aad: &[u8]) -> Vec<u8> {
+            empty_tag::<aes_gcm_siv::Aes256GcmSiv>(key, nonce, aad)
+        }
+    }
+
+    // Keyed BLAKE2b with a 16-byte digest, as in the Python reference's aead_compute_tag
+    // fallback. The nonce must be 24 bytes for interop but is not mixed into the MAC,
+    // so tags do not vary per frame.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Blake2bMacSuite;
+
+    impl AeadSuite for Blake2bMacSuite {
+        fn id(&self) -> &'static str {
+            BLAKE2B_MAC
+        }
+
+        fn nonce_len(&self) -> usize {
+            24
+        }
+
+        fn is_aead(&self) -> bool {
+            false
+        }
+
+        fn raw_tag(&self, key: &[u8], _nonce: &[u8], aad: &[u8]) -> Vec<u8> {
+            use blake2::digest::consts::U16;
+            use blake2::digest::Mac;
+            let mac =
+                <blake2::Blake2bMac<U16> as Mac>::new_from_slice(key).expect("key length checked");
+            mac.chain_update(aad).finalize().into_bytes().to_vec()
+        }
+    }
+
+    // AEAD suites offered by default, in preference order.
+    pub const SUITE_IDS: &[&str] = &[XCHACHA20_POLY1305, CHACHA20_POLY1305, AES_256_GCM_SIV];
+
+    pub fn suite(id: &str) -> Option<&'static dyn AeadSuite> {
+        match id {
+            XCHACHA20_POLY1305 => Some(&XChaCha20Poly1305Suite),
+            CHACHA20_POLY1305 => Some(&ChaCha20Poly1305Suite),
+            AES_256_GCM_SIV => Some(&Aes256GcmSivSuite),
+            BLAKE2B_MAC => Some(&Blake2bMacSuite),
+            _ => None,
+        }
+    }
+}
+
+pub mod envelope {
+    use super::aead::{AeadSuite, XChaCha20Poly1305Suite};
+    use super::aux::{self, AuxBundle};
+    use super::avrodec::Trace;
+    use super::tleb3::Progress;
+    use super::{tleb3, tritpack243, Error};
+    use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+    use chacha20poly1305::XChaCha20Poly1305;
+    use std::ops::Range;
+
+    const MAGIC_B2: [u8; 2] = [0xF3, 0x2A];
+    const VERSION_TRIT: u8 = 1;
+    pub const SCHEMA_ID_32: [u8; 32] = [
+        0xb2, 0xab, 0x81, 0x45, 0x88, 0xf9, 0x9c, 0x87, 0x5d, 0x37, 0xbb, 0x75, 0x46, 0xd0, 0xdf,
+        0x43, 0x69, 0xc2, 0x8b, 0xc5, 0xf6, 0x0c, 0xe3, 0x8a, 0x66, 0x07, 0xda, 0xc4, 0x68, 0x03,
+        0x43, 0x52,
+    ];
+    pub const CONTEXT_ID_32: [u8; 32] = [
+        0xe6, 0x57, 0x2c, 0x0e, 0x61, 0x8f, 0x18, 0xd5, 0x72, 0xd4, 0xc2, 0x96, 0x9d, 0xb4, 0x90,
+        0x96, 0x59, 0xf0, 0x9e, 0xae, 0xf3, 0x2e, 0xc6, 0x6f, 0xbb, 0x80, 0x4b, 0xad, 0x9d, 0x89,
+        0xaa, 0xcd,
+    ];
+
+    // Schema and context IDs carried in every frame. The default is the hypergraph
+    // schema used by the fixtures.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EnvelopeIds {
+        pub schema: [u8; 32],
+        pub context: [u8; 32],
+    }
+
+    impl Default for EnvelopeIds {
+        fn default() -> Self {
+            EnvelopeIds {
+                schema: SCHEMA_ID_32,
+                context: CONTEXT_ID_32,
+            }
+        }
+    }
+
+    fn len_prefix(b: &[u8]) -> Vec<u8> {
+        tleb3::encode_len(b.len() as u64)
+    }
+
+    fn pack_trits(ts: &[u8]) -> Vec<u8> {
+        tritpack243::pack(ts)
+    }
+
+    // Flags trits are [aead, compress, aux]; 2 = set, 0 = clear. The aead trit may
+    // also be 1: the frame is sealed and its payload field carries ciphertext.
+    const AEAD_OFF: u8 = 0;
+    const AEAD_ENCRYPTED: u8 = 1;
+    const AEAD_TAG: u8 = 2;
+
+    pub fn flags_trits(aead: bool, compress: bool, aux: bool) -> [u8; 3] {
+        let t = |b: bool| if b { 2 } else { 0 };
+        [t(aead), t(compress), t(aux)]
+    }
+
+    pub fn build(
+        service: &str,
+        method: &str,
+        payload: &[u8],
+        aux: Option<&[u8]>,
+        aead_tag: Option<&[u8]>,
+        aead_on: bool,
+        compress: bool,
+    ) -> Vec<u8> {
+        build_with_mode(
+            0, service, method, payload, aux, aead_tag, aead_on, compress,
+        )
+    }
+
+    // Mode trit 0 = Path-A (Avro payloads), 1 = Path-B (ternary-native payloads).
+    #[allow(clippy::too_many_arguments)]
+    pub fn build_with_mode(
+        mode: u8,
+        service: &str,
+        method: &str,
+        payload: &[u8],
+        aux: Option<&[u8]>,
+        aead_tag: Option<&[u8]>,
+        aead_on: bool,
+        compress: bool,
+    ) -> Vec<u8> {
+        build_with_ids(
+            &EnvelopeIds::default(),
+            mode,
+            service,
+            method,
+            payload,
+            aux,
+            aead_tag,
+            aead_on,
+            compress,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn build_with_ids(
+        ids: &EnvelopeIds,
+        mode: u8,
+        service: &str,
+        method: &str,
+        payload: &[u8],
+        aux: Option<&[u8]>,
+        aead_tag: Option<&[u8]>,
+        aead_on: bool,
+        compress: bool,
+    ) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        write_header(
+            &mut out,
+            ids,
+            VERSION_TRIT,
+            mode,
+            flags_trits(aead_on, compress, aux.is_some()),
+            service,
+            method,
+        );
+        write_field(&mut out, payload);
+        if let Some(auxb) = aux {
+            write_field(&mut out, auxb);
+        }
+        if let Some(tag) = aead_tag {
+            write_field(&mut out, tag);
+        }
+        out
+    }
+
+    fn write_field(out: &mut Vec<u8>, b: &[u8]) {
+        out.extend(len_prefix(b));
+        out.extend_from_slice(b);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_header(
+        out: &mut Vec<u8>,
+        ids: &EnvelopeIds,
+        version: u8,
+        mode: u8,
+        flags: [u8; 3],
+        service: &str,
+        method: &str,
+    ) {
+        write_field(out, &MAGIC_B2);
+        write_field(out, &pack_trits(&[version]));
+        write_field(out, &pack_trits(&[mode]));
+        write_field(out, &pack_trits(&flags));
+        write_field(out, &ids.schema);
+        write_field(out, &ids.context);
+        write_field(out, service.as_bytes());
+        write_field(out, method.as_bytes());
+    }
+
+    pub fn envelope_with_tag(
+        service: &str,
+        method: &str,
+        payload: &[u8],
+        aux: Option<&[u8]>,
+        key: &[u8; 32],
+        nonce: &[u8; 24],
+    ) -> (Vec<u8>, Vec<u8>) {
+        let mut b = EnvelopeBuilder::new(service, method).payload(payload);
+        if let Some(auxb) = aux {
+            b = b.aux(auxb);
+        }
+        let mut frame = Vec::new();
+        let tag = b.seal(key, nonce, &mut frame);
+        (frame, tag.to_vec())
+    }
+
+    // Protocol versions this crate writes and accepts.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum Version {
+        #[default]
+        V1,
+    }
+
+    impl Version {
+        pub fn trit(self) -> u8 {
+            match self {
+                Version::V1 => VERSION_TRIT,
+            }
+        }
+
+        pub fn from_trit(t: u8) -> Option<Version> {
+            match t {
+                VERSION_TRIT => Some(Version::V1),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Mode {
+        // Avro payloads
+        PathA,
+        // Ternary-native payloads
+        PathB,
+    }
+
+    impl Mode {
+        pub fn trit(self) -> u8 {
+            match self {
+                Mode::PathA => 0,
+                Mode::PathB => 1,
+            }
+        }
+
+        pub fn from_trit(t: u8) -> Option<Mode> {
+            match t {
+                0 => Some(Mode::PathA),
+                1 => Some(Mode::PathB),
+                _ => None,
+            }
+        }
+
+        // Name used in Hello.modes and Choose.mode.
+        pub fn name(self) -> &'static str {
+            match self {
+                Mode::PathA => "Path-A",
+                Mode::PathB => "Path-B",
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Mode> {
+            match name {
+                "Path-A" => Some(Mode::PathA),
+                "Path-B" => Some(Mode::PathB),
+                _ => None,
+            }
+        }
+    }
+
+    // Flags the caller chooses. The AEAD bit is not here: it is set by seal() and
+    // cleared by write(), so a frame can never carry a tag its flags do not announce.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Flags {
+        pub compress: bool,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct EnvelopeBuilder<'a> {
+        version: Version,
+        mode: Mode,
+        flags: Flags,
+        ids: EnvelopeIds,
+        service: &'a str,
+        method: &'a str,
+        payload: &'a [u8],
+        aux: Option<&'a [u8]>,
+    }
+
+    impl<'a> EnvelopeBuilder<'a> {
+        pub fn new(service: &'a str, method: &'a str) -> Self {
+            EnvelopeBuilder {
+                version: Version::V1,
+                mode: Mode::PathA,
+                flags: Flags::default(),
+                ids: EnvelopeIds::default(),
+                service,
+                method,
+                payload: &[],
+                aux: None,
+            }
+        }
+
+        pub fn version(mut self, version: Version) -> Self {
+            self.version = version;
+            self
+        }
+
+        pub fn mode(mut self, mode: Mode) -> Self {
+            self.mode = mode;
+            self
+        }
+
+        pub fn flags(mut self, flags: Flags) -> Self {
+            self.flags = flags;
+            self
+        }
+
+        pub fn ids(mut self, ids: EnvelopeIds) -> Self {
+            self.ids = ids;
+            self
+        }
+
+        pub fn schema(mut self, schema: [u8; 32]) -> Self {
+            self.ids.schema = schema;
+            self
+        }
+
+        pub fn context(mut self, context: [u8; 32]) -> Self {
+            self.ids.context = context;
+            self
+        }
+
+        pub fn service(mut self, service: &'a str) -> Self {
+            self.service = service;
+            self
+        }
+
+        pub fn method(mut self, method: &'a str) -> Self {
+            self.method = method;
+            self
+        }
+
+        pub fn payload(mut self, payload: &'a [u8]) -> Self {
+            self.payload = payload;
+            self
+        }
+
+        pub fn aux(mut self, aux: &'a [u8]) -> Self {
+            self.aux = Some(aux);
+            self
+        }
+
+        pub fn no_aux(mut self) -> Self {
+            self.aux = None;
+            self
+        }
+
+        pub fn aux_bytes(&self) -> Option<&'a [u8]> {
+            self.aux
+        }
+
+        // Writes everything up to the tag field and returns the range of the payload
+        // value bytes in `out`.
+        fn write_unsealed(&self, aead: u8, out: &mut Vec<u8>) -> Range<usize> {
+            let mut flags = flags_trits(false, self.flags.compress, self.aux.is_some());
+            flags[0] = aead;
+            write_header(
+                out,
+                &self.ids,
+                self.version.trit(),
+                self.mode.trit(),
+                flags,
+                self.service,
+                self.method,
+            );
+            out.extend(len_prefix(self.payload));
+            let payload_start = out.len();
+            out.extend_from_slice(self.payload);
+            let payload = payload_start..out.len();
+            if let Some(auxb) = self.aux {
+                write_field(out, auxb);
+            }
+            payload
+        }
+
+        // Appends an unauthenticated frame (AEAD flag off, no tag) to `out`.
+        pub fn write(&self, out: &mut Vec<u8>) {
+            self.write_unsealed(AEAD_OFF, out);
+        }
+
+        // Appends a frame with the AEAD flag on and an XChaCha20-Poly1305 tag over the
+        // preceding frame bytes. Returns the tag.
+        pub fn seal(&self, key: &[u8; 32], nonce: &[u8; 24], out: &mut Vec<u8>) -> [u8; 16] {
+            let tag = self
+                .seal_with(&XChaCha20Poly1305Suite, key, nonce, out)
+                .expect("fixed-size key and nonce");
+            let mut fixed = [0u8; 16];
+            fixed.copy_from_slice(&tag);
+            fixed
+        }
+
+        // seal() under a negotiated suite. Key and nonce sizes are checked before
+        // anything is written to `out`.
+        pub fn seal_with(
+            &self,
+            suite: &dyn AeadSuite,
+            key: &[u8],
+            nonce: &[u8],
+            out: &mut Vec<u8>,
+        ) -> Result<Vec<u8>, Error> {
+            suite.check_params(key, nonce)?;
+            let start = out.len();
+            self.write_unsealed(AEAD_TAG, out);
+            let tag = suite.raw_tag(key, nonce, &out[start..]);
+            write_field(out, &tag);
+            Ok(tag)
+        }
+
+        // Like seal(), but the payload field carries XChaCha20-Poly1305 ciphertext and
+        // the AAD is every frame byte before the tag except the payload value.
+        pub fn seal_encrypted(
+            &self,
+            key: &[u8; 32],
+            nonce: &[u8; 24],
+            out: &mut Vec<u8>,
+        ) -> [u8; 16] {
+            let start = out.len();
+            let payload = self.write_unsealed(AEAD_ENCRYPTED, out);
+            let aad = [&out[start..payload.start], &out[payload.end..]].concat();
+            let aead = XChaCha20Poly1305::new(key.into());
+            let tag = aead
+                .encrypt_in_place_detached(nonce.into(), &aad, &mut out[payload])
+                .expect("encrypt");
+            write_field(out, &tag);
+            tag.into()
+        }
+
+        pub fn to_vec(&self) -> Vec<u8> {
+            let mut out = Vec::new();
+            self.write(&mut out);
+            out
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct DecodedEnvelope {
+        pub magic: Vec<u8>,
+        pub version: Vec<u8>,
+        pub mode: Vec<u8>,
+        pub flags: Vec<u8>,
+        pub schema: [u8; 32],
+        pub context: [u8; 32],
+        pub service: String,
+        pub method: String,
+        pub payload: Vec<u8>,
+        pub aux: Option<Vec<u8>>,
+        pub tag: Option<Vec<u8>>,
+        pub aead_on: bool,
+        // Payload carries ciphertext on the wire. After open() `payload` is the plaintext.
+        pub payload_encrypted: bool,
+        pub compress: bool,
+        pub tag_start: Option<usize>,
+    }
+
+    impl DecodedEnvelope {
+        pub fn ids(&self) -> EnvelopeIds {
+            EnvelopeIds {
+                schema: self.schema,
+                context: self.context,
+            }
+        }
+
+        // The AUX field parsed as a bundle; None when the frame has no AUX.
+        pub fn aux_bundle(&self) -> Result<Option<AuxBundle>, Error> {
+            self.aux.as_deref().map(AuxBundle::decode).transpose()
+        }
+
+        // The AUX Trace, from a bundle or from a reference stream frame's bare Trace.
+        pub fn trace(&self) -> Result<Option<Trace>, Error> {
+            Ok(self.aux.as_deref().map(aux::trace).transpose()?.flatten())
+        }
+
+        // A builder that writes this envelope again, unsealed, with the payload as
+        // decoded (the plaintext after open()).
+        pub fn to_builder(&self) -> Result<EnvelopeBuilder<'_>, Error> {
+            let single = |bytes: &[u8], what: &'static str| -> Result<u8, Error> {
+                match tritpack243::unpack(bytes)?.as_slice() {
+                    [t] => Ok(*t),
+                    _ => Err(Error::Unsupported { what }),
+                }
+            };
+            let version = Version::from_trit(single(&self.version, "multi-trit version")?)
+                .ok_or(Error::Unsupported { what: "version" })?;
+            let mode = Mode::from_trit(single(&self.mode, "multi-trit mode")?)
+                .ok_or(Error::Unsupported { what: "mode" })?;
+            let mut b = EnvelopeBuilder::new(&self.service, &self.method)
+                .version(version)
+                .mode(mode)
+                .flags(Flags {
+                    compress: self.compress,
+                })
+                .ids(self.ids())
+                .payload(&self.payload);
+            if let Some(aux) = self.aux.as_deref() {
+                b = b.aux(aux);
+            }
+            Ok(b)
+        }
+    }
+
+    // A length-prefixed field borrowed from a frame. `start` is the offset of the TLEB3
+    // prefix, `value_start` the offset of the first value byte.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FieldRef<'a> {
+        pub bytes: &'a [u8],
+        pub start: usize,
+        pub value_start: usize,
+    }
+
+    impl FieldRef<'_> {
+        pub fn range(&self) -> Range<usize> {
+            self.value_start..self.end()
+        }
+
+        // Prefix and value together.
+        pub fn span(&self) -> Range<usize> {
+            self.start..self.end()
+        }
+
+        pub fn end(&self) -> usize {
+            self.value_start + self.bytes.len()
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EnvelopeRef<'a> {
+        pub frame: &'a [u8],
+        pub magic: FieldRef<'a>,
+        pub version: FieldRef<'a>,
+        pub mode: FieldRef<'a>,
+        pub flags: FieldRef<'a>,
+        pub schema: FieldRef<'a>,
+        pub context: FieldRef<'a>,
+        pub service: FieldRef<'a>,
+        pub method: FieldRef<'a>,
+        pub payload: FieldRef<'a>,
+        pub aux: Option<FieldRef<'a>>,
+        pub tag: Option<FieldRef<'a>>,
+        pub aead_on: bool,
+        pub payload_encrypted: bool,
+        pub compress: bool,
+        service_str: &'a str,
+        method_str: &'a str,
+        schema_id: &'a [u8; 32],
+        context_id: &'a [u8; 32],
+    }
+
+    impl<'a> EnvelopeRef<'a> {
+        pub fn service_str(&self) -> &'a str {
+            self.service_str
+        }
+
+        pub fn aux_bundle(&self) -> Result<Option<AuxBundle>, Error> {
+            self.aux.map(|f| AuxBundle::decode(f.bytes)).transpose()
+        }
+
+        pub fn method_str(&self) -> &'a str {
+            self.method_str
+        }
+
+        pub fn schema_id(&self) -> &'a [u8; 32] {
+            self.schema_id
+        }
+
+        pub fn context_id(&self) -> &'a [u8; 32] {
+            self.context_id
+        }
+
+        pub fn tag_start(&self) -> Option<usize> {
+            self.tag.map(|t| t.start)
+        }
+
+        pub fn into_owned(self) -> DecodedEnvelope {
+            DecodedEnvelope {
+                magic: self.magic.bytes.to_vec(),
+                version: self.version.bytes.to_vec(),
+                mode: self.mode.bytes.to_vec(),
+                flags: self.flags.bytes.to_vec(),
+                schema: *self.schema_id,
+                context: *self.context_id,
+                service: self.service_str.to_string(),
+                method: self.method_str.to_string(),
+                payload: self.payload.bytes.to_vec(),
+                aux: self.aux.map(|f| f.bytes.to_vec()),
+                tag: self.tag.map(|f| f.bytes.to_vec()),
+                aead_on: self.aead_on,
+                payload_encrypted: self.payload_encrypted,
+                compress: self.compress,
+                tag_start: self.tag_start(),
+            }
+        }
+    }
+
+    impl From<EnvelopeRef<'_>> for DecodedEnvelope {
+        fn from(r: EnvelopeRef<'_>) -> DecodedEnvelope {
+            r.into_owned()
+        }
+    }
+
+    fn read_field<'a>(
+        frame: &'a [u8],
+        off: usize,
+        field: &'static str,
+    ) -> Result<FieldRef<'a>, Error> {
+        let (len, new_off) = tleb3::decode_len(frame, off)?;
+        let val_start = new_off;
+        let val_end = match usize::try_from(len)
+            .ok()
+            .and_then(|l| val_start.checked_add(l))
+        {
+            Some(end) if end <= frame.len() => end,
+            _ => return Err(Error::FieldOverrun { field, offset: off }),
+        };
+        Ok(FieldRef {
+            bytes: &frame[val_start..val_end],
+            start: off,
+            value_start: val_start,
+        })
+    }
+
+    fn utf8_field<'a>(f: &FieldRef<'a>, field: &'static str) -> Result<&'a str, Error> {
+        std::str::from_utf8(f.bytes).map_err(|_| Error::NotUtf8 {
+            field,
+            offset: f.start,
+        })
+    }
+
+    fn id_field<'a>(f: &FieldRef<'a>, field: &'static str) -> Result<&'a [u8; 32], Error> {
+        f.bytes.try_into().map_err(|_| Error::BadIdLength {
+            field,
+            len: f.bytes.len(),
+            offset: f.start,
+        })
+    }
+
+    // Routing view of a frame: everything up to METHOD, with magic and version checked.
+    // The payload, aux and tag fields are not read.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RouteHeader<'a> {
+        pub mode: u8,
+        pub aead_on: bool,
+        pub payload_encrypted: bool,
+        pub compress: bool,
+        pub schema: &'a [u8; 32],
+        pub context: &'a [u8; 32],
+        pub service: &'a str,
+        pub method: &'a str,
+        // Offset of the payload field's length prefix.
+        pub payload_offset: usize,
+    }
+
+    struct Header<'a> {
+        magic: FieldRef<'a>,
+        version: FieldRef<'a>,
+        mode: FieldRef<'a>,
+        flags: FieldRef<'a>,
+        schema: FieldRef<'a>,
+        context: FieldRef<'a>,
+        service: FieldRef<'a>,
+        method: FieldRef<'a>,
+    }
+
+    // Every entry point reads the header here, so all of them agree on which magic and
+    // version they accept.
+    fn read_header(frame: &[u8]) -> Result<Header<'_>, Error> {
+        let magic = read_field(frame, 0, "magic")?;
+        if magic.bytes != MAGIC_B2 {
+            return Err(Error::BadMagic {
+                offset: magic.start,
+            });
+        }
+        let version = read_field(frame, magic.end(), "version")?;
+        if tritpack243::unpack(version.bytes)? != [VERSION_TRIT] {
+            return Err(Error::UnsupportedVersion {
+                offset: version.start,
+            });
+        }
+        let mode = read_field(frame, version.end(), "mode")?;
+        let flags = read_field(frame, mode.end(), "flags")?;
+        let schema = read_field(frame, flags.end(), "schema")?;
+        let context = read_field(frame, schema.end(), "context")?;
+        let service = read_field(frame, context.end(), "service")?;
+        let method = read_field(frame, service.end(), "method")?;
+        Ok(Header {
+            magic,
+            version,
+            mode,
+            flags,
+            schema,
+            context,
+            service,
+            method,
+        })
+    }
+
+    struct FlagBits {
+        aead_on: bool,
+        payload_encrypted: bool,
+        compress: bool,
+        aux_on: bool,
+    }
+
+    fn flag_bits(flags: &FieldRef) -> Result<FlagBits, Error> {
+        let trits = tritpack243::unpack(flags.bytes)?;
+        let bit = |t: u8| match t {
+            0 => Ok(false),
+            2 => Ok(true),
+            _ => Err(Error::InvalidFlags {
+                offset: flags.start,
+            }),
+        };
+        match trits.as_slice() {
+            [a, c, x] => Ok(FlagBits {
+                aead_on: *a != AEAD_OFF,
+                payload_encrypted: *a == AEAD_ENCRYPTED,
+                compress: bit(*c)?,
+                aux_on: bit(*x)?,
+            }),
+            _ => Err(Error::InvalidFlags {
+                offset: flags.start,
+            }),
+        }
+    }
+
+    pub fn peek_route(frame: &[u8]) -> Result<RouteHeader<'_>, Error> {
+        let h = read_header(frame)?;
+        let mode = match tritpack243::unpack(h.mode.bytes)?.as_slice() {
+            [m] => *m,
+            _ => {
+                return Err(Error::Unsupported {
+                    what: "multi-trit mode",
+                })
+            }
+        };
+        let FlagBits {
+            aead_on,
+            payload_encrypted,
+            compress,
+            ..
+        } = flag_bits(&h.flags)?;
+        Ok(RouteHeader {
+            mode,
+            aead_on,
+            payload_encrypted,
+            compress,
+            schema: id_field(&h.schema, "schema")?,
+            context: id_field(&h.context, "context")?,
+            service: utf8_field(&h.service, "service")?,
+            method: utf8_field(&h.method, "method")?,
+            payload_offset: h.method.end(),
+        })
+    }
+
+    pub fn decode_ref(frame: &[u8]) -> Result<EnvelopeRef<'_>, Error> {
+        let Header {
+            magic,
+            version,
+            mode,
+            flags,
+            schema,
+            context,
+            service,
+            method,
+        } = read_header(frame)?;
+        let payload = read_field(frame, method.end(), "payload")?;
+        let mut off = payload.end();
+
+        let FlagBits {
+            aead_on,
+            payload_encrypted,
+            compress,
+            aux_on,
+        } = flag_bits(&flags)?;
+
+        // The flags say which trailing fields follow the payload; nothing is inferred
+        // from how many bytes remain.
+        let mut trailing = |on: bool, field: &'static str| -> Result<Option<FieldRef>, Error> {
+            if !on {
+                return Ok(None);
+            }
+            if off == frame.len() {
+                return Err(Error::MissingField { field });
+            }
+            let f = read_field(frame, off, field)?;
+            off = f.end();
+            Ok(Some(f))
+        };
+        let aux = trailing(aux_on, "aux")?;
+        let tag = trailing(aead_on, "tag")?;
+        if off != frame.len() {
+            return Err(Error::TrailingBytes {
+                what: "envelope",
+                offset: off,
+            });
+        }
+        Ok(EnvelopeRef {
+            frame,
+            magic,
+            version,
+            mode,
+            flags,
+            schema,
+            context,
+            service,
+            method,
+            payload,
+            aux,
+            tag,
+            aead_on,
+            payload_encrypted,
+            compress,
+            service_str: utf8_field(&service, "service")?,
+            method_str: utf8_field(&method, "method")?,
+            schema_id: id_field(&schema, "schema")?,
+            context_id: id_field(&context, "context")?,
+        })
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<DecodedEnvelope, Error> {
+        decode_ref(frame).map(EnvelopeRef::into_owned)
+    }
+
+    // Decodes a sealed frame and checks its tag over the bytes before the tag field.
+    // Nothing from the frame is returned unless the tag verifies. Encrypted payloads
+    // are returned decrypted.
+    pub fn open(frame: &[u8], key: &[u8; 32], nonce: &[u8; 24]) -> Result<DecodedEnvelope, Error> {
+        open_with(&XChaCha20Poly1305Suite, frame, key, nonce)
+    }
+
+    // open() under a negotiated suite. Encrypted payloads are XChaCha20-Poly1305 only.
+    pub fn open_with(
+        suite: &dyn AeadSuite,
+        frame: &[u8],
+        key: &[u8],
+        nonce: &[u8],
+    ) -> Result<DecodedEnvelope, Error> {
+        let env = decode_ref(frame)?;
+        let tag = env.tag.ok_or(Error::MissingField { field: "tag" })?;
+        if env.payload_encrypted {
+            if suite.id() != XChaCha20Poly1305Suite.id() {
+                return Err(Error::Unsupported {
+                    what: "encrypted payload outside XCHACHA20-POLY1305",
+                });
+            }
+            suite.check_params(key, nonce)?;
+            if tag.bytes.len() != 16 {
+                return Err(Error::AuthFailed);
+            }
+            let payload = env.payload;
+            let aad = [
+                &frame[..payload.value_start],
+                &frame[payload.end()..tag.start],
+            ]
+            .concat();
+            let mut plaintext = payload.bytes.to_vec();
+            XChaCha20Poly1305::new(key.into())
+                .decrypt_in_place_detached(nonce.into(), &aad, &mut plaintext, tag.bytes.into())
+                .map_err(|_| Error::AuthFailed)?;
+            let mut out = env.into_owned();
+            out.payload = plaintext;
+            return Ok(out);
+        }
+        suite.verify_tag(key, nonce, &frame[..tag.start], tag.bytes)?;
+        Ok(env.into_owned())
+    }
+
+    // Per-chunk nonces for a stream: the last 4 bytes of the base are a big-endian
+    // counter and chunk i uses counter + i. Running past 2^32 - 1 is an error rather
+    // than a wrap, since a wrapped counter would reuse a nonce under the same key.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct StreamNonce {
+        base: [u8; 24],
+        next: u64,
+    }
+
+    impl StreamNonce {
+        pub fn new(base: [u8; 24]) -> Self {
+            StreamNonce { base, next: 0 }
+        }
+
+        pub fn derive(&self, chunk_index: u64) -> Result<[u8; 24], Error> {
+            let mut ctr = [0u8; 4];
+            ctr.copy_from_slice(&self.base[20..]);
+            let ctr = u64::from(u32::from_be_bytes(ctr))
+                .checked_add(chunk_index)
+                .and_then(|c| u32::try_from(c).ok())
+                .ok_or(Error::NonceExhausted { chunk_index })?;
+            let mut nonce = self.base;
+            nonce[20..].copy_from_slice(&ctr.to_be_bytes());
+            Ok(nonce)
+        }
+
+        // Index of the chunk the next seal()/open() will use.
+        pub fn chunk_index(&self) -> u64 {
+            self.next
+        }
+
+        pub fn next_nonce(&mut self) -> Result<[u8; 24], Error> {
+            let nonce = self.derive(self.next)?;
+            self.next += 1;
+            Ok(nonce)
+        }
+
+        // Seals the next chunk (OPEN, DATA..., CLOSE in order) into `out`.
+        pub fn seal(
+            &mut self,
+            builder: &EnvelopeBuilder,
+            key: &[u8; 32],
+            out: &mut Vec<u8>,
+        ) -> Result<[u8; 16], Error> {
+            let nonce = self.next_nonce()?;
+            Ok(builder.seal(key, &nonce, out))
+        }
+
+        // Opens the next chunk. The counter only advances when the tag verifies.
+        pub fn open(&mut self, frame: &[u8], key: &[u8; 32]) -> Result<DecodedEnvelope, Error> {
+            let env = open(frame, key, &self.derive(self.next)?)?;
+            self.next += 1;
+            Ok(env)
+        }
+    }
+
+    pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+    // Fields through the payload; AUX and tag follow only when the flags announce them.
+    const BASE_FIELDS: usize = 9;
+    const FLAGS_FIELD: usize = 3;
+
+    // Resumable decoder for frames sent back to back on a byte stream. A frame's extent
+    // follows from its own fields (the flags say whether AUX and tag follow the
+    // payload), so the decoder walks the length prefixes as bytes arrive. Feed it
+    // chunks and call next_frame() until it reports NeedMore.
+    //
+    // A framing error leaves the position of the next frame unknown, so it is sticky:
+    // every later call returns the same error. A frame that is fully delimited but
+    // fails to decode is consumed and the stream continues after it.
+    #[derive(Debug, Clone)]
+    pub struct StreamDecoder {
+        buf: Vec<u8>,
+        // End of the last complete field of the frame at the front of `buf`.
+        off: usize,
+        // Fields of that frame read so far, and how many it has.
+        field: usize,
+        fields: usize,
+        max_frame_len: usize,
+        failed: Option<Error>,
+    }
+
+    impl Default for StreamDecoder {
+        fn default() -> StreamDecoder {
+            StreamDecoder::with_max_frame_len(DEFAULT_MAX_FRAME_LEN)
+        }
+    }
+
+    impl StreamDecoder {
+        pub fn new() -> StreamDecoder {
+            StreamDecoder::default()
+        }
+
+        pub fn with_max_frame_len(max_frame_len: usize) -> StreamDecoder {
+            StreamDecoder {
+                buf: Vec::new(),
+                off: 0,
+                field: 0,
+                fields: BASE_FIELDS,
+                max_frame_len,
+                failed: None,
+            }
+        }
+
+        pub fn feed(&mut self, chunk: &[u8]) {
+            self.buf.extend_from_slice(chunk);
+        }
+
+        pub fn next_frame(&mut self) -> Result<Progress<DecodedEnvelope>, Error> {
+            if let Some(e) = &self.failed {
+                return Err(e.clone());
+            }
+            match self.scan() {
+                Ok(Progress::Complete(frame_len)) => {
+                    let frame: Vec<u8> = self.buf.drain(..frame_len).collect();
+                    self.off = 0;
+                    self.field = 0;
+                    self.fields = BASE_FIELDS;
+                    decode(&frame).map(Progress::Complete)
+                }
+                Ok(Progress::NeedMore(n)) => Ok(Progress::NeedMore(n)),
+                Err(e) => {
+                    self.failed = Some(e.clone());
+                    Err(e)
+                }
+            }
+        }
+
+        // Advances over the complete fields in `buf`. Completes with the frame length
+        // once the whole frame at the front is buffered.
+        fn scan(&mut self) -> Result<Progress<usize>, Error> {
+            while self.field < self.fields {
+                let (len, value_start) = match tleb3::decode_len(&self.buf, self.off) {
+                    Ok(v) => v,
+                    Err(Error::TlebEof { .. }) | Err(Error::TruncatedTail { .. }) => {
+                        return Ok(Progress::NeedMore(1))
+                    }
+                    Err(e) => return Err(e),
+                };
+                let end = (value_start as u64).saturating_add(len);
+                if end > self.max_frame_len as u64 {
+                    return Err(Error::FrameTooLarge {
+                        len: end,
+                        max: self.max_frame_len,
+                    });
+                }
+                let end = end as usize;
+                if end > self.buf.len() {
+                    return Ok(Progress::NeedMore(end - self.buf.len()));
+                }
+                let field = FieldRef {
+                    bytes: &self.buf[value_start..end],
+                    start: self.off,
+                    value_start,
+                };
+                if self.field == 0 && field.bytes != MAGIC_B2 {
+                    return Err(Error::BadMagic { offset: 0 });
+                }
+                if self.field == FLAGS_FIELD {
+                    let bits = flag_bits(&field)?;
+                    self.fields = BASE_FIELDS + bits.aux_on as usize + bits.aead_on as usize;
+                }
+                self.off = end;
+                self.field += 1;
+            }
+            Ok(Progress::Complete(self.off))
+        }
+
+        // Bytes received but not yet returned as part of a frame.
+        pub fn buffered(&self) -> usize {
+            self.buf.len()
+        }
+    }
+}
+
+// Receiver-side replay protection. Both guards check a frame's tag first and only
+// record its nonce once the tag verifies, so forged frames cannot poison the state.
+pub mod replay {
+    use super::aead::AeadSuite;
+    use super::envelope::{self, DecodedEnvelope};
+    use super::Error;
+    use std::collections::{HashSet, VecDeque};
+
+    pub const WINDOW: u64 = 64;
+
+    // Sliding window over stream nonce counters (the big-endian last 4 bytes, as
+    // derived by envelope::StreamNonce). Accepts counters up to WINDOW - 1 behind the
+    // highest one seen, each at most once.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct ReplayWindow {
+        top: Option<u64>,
+        // bit i set = counter top - i has been accepted
+        bitmap: u64,
+    }
+
+    impl ReplayWindow {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn check(&self, counter: u64) -> Result<(), Error> {
+            let top = match self.top {
+                Some(top) if counter <= top => top,
+                _ => return Ok(()),
+            };
+            let age = top - counter;
+            if age >= WINDOW || self.bitmap & (1 << age) != 0 {
+                return Err(Error::Replayed);
+            }
+            Ok(())
+        }
+
+        // Records `counter` as seen. Counters already behind the window are ignored;
+        // check() rejects them anyway.
+        pub fn commit(&mut self, counter: u64) {
+            match self.top {
+                Some(top) if counter <= top => {
+                    let age = top - counter;
+                    if age < WINDOW {
+                        self.bitmap |= 1 << age;
+                    }
+                }
+                Some(top) => {
+                    let shift = counter - top;
+                    self.bitmap = if shift >= WINDOW {
+                        0
+                    } else {
+                        self.bitmap << shift
+                    };
+                    self.bitmap |= 1;
+                    self.top = Some(counter);
+                }
+                None => {
+                    self.bitmap = 1;
+                    self.top = Some(counter);
+                }
+            }
+        }
+
+        // Runs `verify` on a frame sealed under `nonce` unless the nonce's counter has
+        // already been accepted, and records the counter only once `verify` succeeds.
+        // The verifier can be any opener (envelope::open, another suite, a keyring),
+        // whatever its nonce length.
+        pub fn guard<T>(
+            &mut self,
+            nonce: &[u8],
+            verify: impl FnOnce() -> Result<T, Error>,
+        ) -> Result<T, Error> {
+            let counter = counter(nonce)?;
+            self.check(counter)?;
+            let out = verify()?;
+            self.commit(counter);
+            Ok(out)
+        }
+
+        pub fn open(
+            &mut self,
+            frame: &[u8],
+            key: &[u8; 32],
+            nonce: &[u8; 24],
+        ) -> Result<DecodedEnvelope, Error> {
+            self.guard(nonce, || envelope::open(frame, key, nonce))
+        }
+
+        pub fn open_with(
+            &mut self,
+            suite: &dyn AeadSuite,
+            frame: &[u8],
+            key: &[u8],
+            nonce: &[u8],
+        ) -> Result<DecodedEnvelope, Error> {
+            self.guard(nonce, || envelope::open_with(suite, frame, key, nonce))
+        }
+    }
+
+    // The stream counter: the big-endian last 4 bytes of the nonce.
+    fn counter(nonce: &[u8]) -> Result<u64, Error> {
+        let tail = nonce
+            .len()
+            .checked_sub(4)
+            .and_then(|start| <[u8; 4]>::try_from(&nonce[start..]).ok())
+            .ok_or(Error::Unsupported {
+                what: "nonce without a 4-byte counter",
+            })?;
+        Ok(u64::from(u32::from_be_bytes(tail)))
+    }
+
+    // Bounded set of nonces seen on unary frames. Once full, the oldest nonce is
+    // forgotten, so capacity bounds how far back a replay is caught.
+    #[derive(Debug, Clone)]
+    pub struct NonceCache {
+        seen: HashSet<Vec<u8>>,
+        order: VecDeque<Vec<u8>>,
+        capacity: usize,
+    }
+
+    impl NonceCache {
+        // A capacity of 0 would catch no replays at all, so it is raised to 1.
+        pub fn new(capacity: usize) -> Self {
+            let capacity = capacity.max(1);
+            NonceCache {
+                seen: HashSet::with_capacity(capacity),
+                order: VecDeque::with_capacity(capacity),
+                capacity,
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.order.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.order.is_empty()
+        }
+
+        pub fn check(&self, nonce: &[u8]) -> Result<(), Error> {
+            if self.seen.contains(nonce) {
+                return Err(Error::Replayed);
+            }
+            Ok(())
+        }
+
+        pub fn commit(&mut self, nonce: &[u8]) {
+            if !self.seen.insert(nonce.to_vec()) {
+                return;
+            }
+            if self.order.len() == self.capacity {
+                if let Some(old) = self.order.pop_front() {
+                    self.seen.remove(&old);
+                }
+            }
+            self.order.push_back(nonce.to_vec());
+        }
+
+        // As ReplayWindow::guard, keyed on the whole nonce.
+        pub fn guard<T>(
+            &mut self,
+            nonce: &[u8],
+            verify: impl FnOnce() -> Result<T, Error>,
+        ) -> Result<T, Error> {
+            self.check(nonce)?;
+            let out = verify()?;
+            self.commit(nonce);
+            Ok(out)
+        }
+
+        pub fn open(
+            &mut self,
+            frame: &[u8],
+            key: &[u8; 32],
+            nonce: &[u8; 24],
+        ) -> Result<DecodedEnvelope, Error> {
+            self.guard(nonce, || envelope::open(frame, key, nonce))
+        }
+
+        pub fn open_with(
+            &mut self,
+            suite: &dyn AeadSuite,
+            frame: &[u8],
+            key: &[u8],
+            nonce: &[u8],
+        ) -> Result<DecodedEnvelope, Error> {
+            self.guard(nonce, || envelope::open_with(suite, frame, key, nonce))
+        }
+    }
+}
+
+// AUX field contents: an Avro map<string, bytes> of named entries, written in name
+// order so the same bundle always encodes to the same bytes.
+pub mod aux {
+    use super::avrodec::{self, PoE, Sig, Trace};
+    use super::{avroenc, Error};
+    use std::collections::BTreeMap;
+
+    pub const KEY_ID: &str = "kid";
+    // Avro-encoded Trace record.
+    pub const TRACE: &str = "trace";
+    // W3C tracestate header value, UTF-8.
+    pub const TRACESTATE: &str = "tracestate";
+    // Avro-encoded PoE record.
+    pub const POE: &str = "poe";
+    // Avro-encoded Sig record.
+    pub const SIG: &str = "sig";
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct AuxBundle {
+        entries: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl AuxBundle {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn get(&self, name: &str) -> Option<&[u8]> {
+            self.entries.get(name).map(Vec::as_slice)
+        }
+
+        pub fn insert(&mut self, name: &str, value: Vec<u8>) -> Option<Vec<u8>> {
+            self.entries.insert(name.to_string(), value)
+        }
+
+        pub fn remove(&mut self, name: &str) -> Option<Vec<u8>> {
+            self.entries.remove(name)
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+            self.entries.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+        }
+
+        pub fn key_id(&self) -> Result<Option<&str>, Error> {
+            self.get(KEY_ID)
+                .map(|b| {
+                    std::str::from_utf8(b).map_err(|_| Error::NotUtf8 {
+                        field: "key id",
+                        offset: 0,
+                    })
+                })
+                .transpose()
+        }
+
+        pub fn set_key_id(&mut self, key_id: &str) {
+            self.insert(KEY_ID, key_id.as_bytes().to_vec());
+        }
+
+        pub fn trace(&self) -> Result<Option<Trace>, Error> {
+            self.get(TRACE).map(avrodec::dec_trace_record).transpose()
+        }
+
+        pub fn set_trace(&mut self, trace: &Trace) {
+            self.insert(TRACE, avrodec::enc_trace(trace));
+        }
+
+        pub fn poe(&self) -> Result<Option<PoE>, Error> {
+            self.get(POE).map(avrodec::dec_poe_record).transpose()
+        }
+
+        pub fn set_poe(&mut self, poe: &PoE) {
+            self.insert(POE, avrodec::enc_poe(poe));
+        }
+
+        pub fn sig(&self) -> Result<Option<Sig>, Error> {
+            self.get(SIG).map(avrodec::dec_sig_record).transpose()
+        }
+
+        pub fn set_sig(&mut self, sig: &Sig) {
+            self.insert(SIG, avrodec::enc_sig(sig));
+        }
+
+        pub fn encode(&self) -> Vec<u8> {
+            let entries: Vec<(&str, &[u8])> = self.iter().collect();
+            avroenc::enc_map_bytes(&entries)
+        }
+
+        pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+            let (entries, off) = avrodec::dec_map_bytes(bytes, 0)?;
+            if off != bytes.len() {
+                return Err(Error::TrailingBytes {
+                    what: "aux bundle",
+                    offset: off,
+                });
+            }
+            let mut bundle = AuxBundle::new();
+            for (name, value) in entries {
+                if bundle.entries.insert(name, value).is_some() {
+                    return Err(Error::Unsupported {
+                        what: "duplicate aux entry",
+                    });
+                }
+            }
+            Ok(bundle)
+        }
+    }
+
+    // How a frame's AUX bytes read. The Python reference's stream encoders write their
+    // record fields as AUX directly instead of a bundle: OPEN carries a Trace, DATA a
+    // Trace followed by the chunk index, CLOSE a PoE.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum AuxKind {
+        Bundle(AuxBundle),
+        // Leading Trace of a reference stream frame's AUX.
+        LegacyTrace(Trace),
+        // Neither of the above, e.g. a bare PoE. Receivers ignore it.
+        Opaque,
+    }
+
+    // AUX that starts with a well-framed map is a bundle, so a bundle that fails to
+    // decode (trailing bytes, a duplicate entry) is an error rather than a guess at
+    // the legacy forms.
+    pub fn classify(bytes: &[u8]) -> Result<AuxKind, Error> {
+        if avrodec::dec_map_bytes(bytes, 0).is_ok() {
+            return AuxBundle::decode(bytes).map(AuxKind::Bundle);
+        }
+        Ok(match avrodec::dec_trace(bytes, 0) {
+            Ok((trace, _)) => AuxKind::LegacyTrace(trace),
+            Err(_) => AuxKind::Opaque,
+        })
+    }
+
+    // The Trace a frame's AUX carries in either form. A malformed bundle or `trace`
+    // entry is an error; AUX that is neither form has no Trace.
+    pub fn trace(bytes: &[u8]) -> Result<Option<Trace>, Error> {
+        match classify(bytes)? {
+            AuxKind::Bundle(bundle) => bundle.trace(),
+            AuxKind::LegacyTrace(trace) => Ok(Some(trace)),
+            AuxKind::Opaque => Ok(None),
+        }
+    }
+
+    // AUX bytes carrying just a Trace, for EnvelopeBuilder::aux.
+    pub fn trace_aux(trace: &Trace) -> Vec<u8> {
+        let mut bundle = AuxBundle::new();
+        bundle.set_trace(trace);
+        bundle.encode()
+    }
+}
+
+// Bridge between the Trace record and W3C Trace Context headers
+// (https://www.w3.org/TR/trace-context/). A frame's Trace names the sender's span, so
+// traceparent's parent-id maps to Trace.span_id; trace flags are not carried.
+pub mod tracecontext {
+    use super::aux::{self, AuxBundle, AuxKind, TRACESTATE};
+    use super::avrodec::Trace;
+    use super::envelope::DecodedEnvelope;
+    use super::Error;
+    use std::fmt;
+
+    pub const FLAG_SAMPLED: u8 = 0x01;
+    const MAX_TRACESTATE_MEMBERS: usize = 32;
+
+    fn bad(what: &'static str) -> Error {
+        Error::BadTraceContext { what }
+    }
+
+    fn lower_hex(s: &str, len: usize) -> bool {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    }
+
+    // Lowercase hex of exactly `len` chars, not all zeros.
+    fn check_id(s: &str, len: usize, what: &'static str) -> Result<(), Error> {
+        if !lower_hex(s, len) || s.bytes().all(|b| b == b'0') {
+            return Err(bad(what));
+        }
+        Ok(())
+    }
+
+    // version and trace-flags: exactly two lowercase hex digits.
+    fn hex_byte(s: &str, what: &'static str) -> Result<u8, Error> {
+        if !lower_hex(s, 2) {
+            return Err(bad(what));
+        }
+        u8::from_str_radix(s, 16).map_err(|_| bad(what))
+    }
+
+    pub fn check_trace_id(s: &str) -> Result<(), Error> {
+        check_id(s, 32, "trace-id")
+    }
+
+    pub fn check_span_id(s: &str) -> Result<(), Error> {
+        check_id(s, 16, "span-id")
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TraceParent {
+        pub trace_id: String,
+        pub parent_id: String,
+        pub flags: u8,
+    }
+
+    impl TraceParent {
+        pub fn parse(header: &str) -> Result<Self, Error> {
+            let parts: Vec<&str> = header.trim().split('-').collect();
+            if parts.len() < 4 {
+                return Err(bad("traceparent"));
+            }
+            let version = hex_byte(parts[0], "version")?;
+            // version 00 has exactly four fields; later versions may append more
+            if version == 0xff || (version == 0 && parts.len() != 4) {
+                return Err(bad("version"));
+            }
+            check_trace_id(parts[1])?;
+            check_span_id(parts[2])?;
+            let flags = hex_byte(parts[3], "trace-flags")?;
+            Ok(TraceParent {
+                trace_id: parts[1].to_string(),
+                parent_id: parts[2].to_string(),
+                flags,
+            })
+        }
+
+        pub fn from_trace(trace: &Trace, flags: u8) -> Result<Self, Error> {
+            check_trace_id(&trace.trace_id)?;
+            check_span_id(&trace.span_id)?;
+            Ok(TraceParent {
+                trace_id: trace.trace_id.clone(),
+                parent_id: trace.span_id.clone(),
+                flags,
+            })
+        }
+
+        // The caller's span as a Trace record; its own parent is not in the header.
+        pub fn to_trace(&self) -> Trace {
+            Trace {
+                trace_id: self.trace_id.clone(),
+                span_id: self.parent_id.clone(),
+                parent_span_id: None,
+            }
+        }
+
+        pub fn sampled(&self) -> bool {
+            self.flags & FLAG_SAMPLED != 0
+        }
+    }
+
+    impl fmt::Display for TraceParent {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "00-{}-{}-{:02x}",
+                self.trace_id, self.parent_id, self.flags
+            )
+        }
+    }
+
+    fn valid_key(k: &str) -> bool {
+        let (tenant, system) = match k.split_once('@') {
+            Some((t, s)) => (t, Some(s)),
+            None => (k, None),
+        };
+        let simple = |s: &str, max: usize, first_digit_ok: bool| {
+            let b = s.as_bytes();
+            !b.is_empty()
+                && b.len() <= max
+                && (b[0].is_ascii_lowercase() || (first_digit_ok && b[0].is_ascii_digit()))
+                && b.iter()
+                    .all(|&c| c.is_ascii_lowercase() || c.is_ascii_digit() || b"_-*/".contains(&c))
+        };
+        match system {
+            None => simple(tenant, 256, false),
+            Some(system) => simple(tenant, 241, true) && simple(system, 14, false),
+        }
+    }
+
+    fn valid_value(v: &str) -> bool {
+        !v.is_empty()
+            && v.len() <= 256
+            && !v.ends_with(' ')
+            && v.bytes()
+                .all(|c| (0x20..=0x7e).contains(&c) && c != b',' && c != b'=')
+    }
+
+    // tracestate list members in header order (leftmost = most recent vendor).
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct TraceState {
+        pub members: Vec<(String, String)>,
+    }
+
+    impl TraceState {
+        pub fn parse(header: &str) -> Result<Self, Error> {
+            let mut members: Vec<(String, String)> = Vec::new();
+            for m in header.split(',').map(|m| m.trim_matches([' ', '\t'])) {
+                if m.is_empty() {
+                    continue;
+                }
+                let (k, v) = m.split_once('=').ok_or(bad("tracestate member"))?;
+                if !valid_key(k) {
+                    return Err(bad("tracestate key"));
+                }
+                if !valid_value(v) {
+                    return Err(bad("tracestate value"));
+                }
+                if members.iter().any(|(seen, _)| seen == k) {
+                    return Err(bad("duplicate tracestate key"));
+                }
+                members.push((k.to_string(), v.to_string()));
+            }
+            if members.len() > MAX_TRACESTATE_MEMBERS {
+                return Err(bad("tracestate members"));
+            }
+            Ok(TraceState { members })
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.members.is_empty()
+        }
+    }
+
+    impl fmt::Display for TraceState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (i, (k, v)) in self.members.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(",")?;
+                }
+                write!(f, "{}={}", k, v)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Adds the caller's traceparent (and tracestate, if any) to an AUX bundle.
+    pub fn inject(bundle: &mut AuxBundle, parent: &TraceParent, state: Option<&TraceState>) {
+        bundle.set_trace(&parent.to_trace());
+        match state.filter(|s| !s.is_empty()) {
+            Some(s) => bundle.insert(TRACESTATE, s.to_string().into_bytes()),
+            None => bundle.remove(TRACESTATE),
+        };
+    }
+
+    // traceparent/tracestate for a frame whose AUX carries a Trace, either as a bundle
+    // entry or as a reference stream frame's bare Trace (which has no tracestate).
+    // Flags are not on the wire, so the caller supplies them.
+    pub fn extract(
+        env: &DecodedEnvelope,
+        flags: u8,
+    ) -> Result<Option<(TraceParent, TraceState)>, Error> {
+        let (trace, bundle) = match env.aux.as_deref().map(aux::classify).transpose()? {
+            Some(AuxKind::Bundle(bundle)) => match bundle.trace()? {
+                Some(trace) => (trace, bundle),
+                None => return Ok(None),
+            },
+            Some(AuxKind::LegacyTrace(trace)) => (trace, AuxBundle::new()),
+            Some(AuxKind::Opaque) | None => return Ok(None),
+        };
+        let parent = TraceParent::from_trace(&trace, flags)?;
+        let state = match bundle.get(TRACESTATE) {
+            None => TraceState::default(),
+            Some(b) => TraceState::parse(std::str::from_utf8(b).map_err(|_| bad("tracestate"))?)?,
+        };
+        Ok(Some((parent, state)))
+    }
+
+    // Server hook: the Trace for a new span handling `env`. With a Trace AUX present
+    // (bundle entry or a reference stream frame's bare Trace) the span joins the
+    // caller's trace as its child; otherwise None and the server starts a root span of
+    // its own. The incoming ids are carried over as sent: Trace ids on the wire need not
+    // be W3C ids (the reference fixtures use ids like "t-01"), and only extract() needs
+    // them to be.
+    pub fn server_span(env: &DecodedEnvelope, span_id: &str) -> Result<Option<Trace>, Error> {
+        check_span_id(span_id)?;
+        let Some(incoming) = env.trace()? else {
+            return Ok(None);
+        };
+        Ok(Some(Trace {
+            trace_id: incoming.trace_id,
+            span_id: span_id.to_string(),
+            parent_span_id: Some(incoming.span_id),
+        }))
+    }
+}
+
+// Proof-of-Execution records. A PoE names the frame it covers by schema/context IDs and
+// method, and commits to its payload with a BLAKE3 digest. For encrypted frames the
+// digest is over the plaintext, i.e. the payload as returned by envelope::open.
+pub mod poe {
+    use super::avrodec::PoE;
+    use super::envelope::DecodedEnvelope;
+    use super::Error;
+    use subtle::ConstantTimeEq;
+
+    pub fn payload_digest(payload: &[u8]) -> [u8; 32] {
+        *blake3::hash(payload).as_bytes()
+    }
+
+    pub fn issue(env: &DecodedEnvelope, timestamp: i64, signer: &str) -> PoE {
+        PoE {
+            schema_id: hex::encode(env.schema),
+            context_id: hex::encode(env.context),
+            payload_digest: payload_digest(&env.payload).to_vec(),
+            method: env.method.clone(),
+            timestamp,
+            signer: signer.to_string(),
+        }
+    }
+
+    // Checks that `poe` describes `env`. Timestamp and signer are the issuer's claims
+    // and are left to the caller's policy.
+    pub fn verify(poe: &PoE, env: &DecodedEnvelope) -> Result<(), Error> {
+        if poe.schema_id != hex::encode(env.schema) {
+            return Err(Error::PoEMismatch { field: "schema_id" });
+        }
+        if poe.context_id != hex::encode(env.context) {
+            return Err(Error::PoEMismatch {
+                field: "context_id",
+            });
+        }
+        if poe.method != env.method {
+            return Err(Error::PoEMismatch { field: "method" });
+        }
+        let digest = payload_digest(&env.payload);
+        if !bool::from(poe.payload_digest.ct_eq(&digest)) {
+            return Err(Error::PoEMismatch {
+                field: "payload_digest",
+            });
+        }
+        Ok(())
+    }
+}
+
+// Ed25519 signatures carried as a Sig entry in the AUX bundle. Unlike the AEAD tag,
+// which any holder of the shared key can produce, a signature names one signer.
+//
+// Envelope scope signs the frame as EnvelopeBuilder::write would emit it with the
+// AUX bundle minus the sig entry (no AUX field if nothing else is left), so the AEAD
+// flag and tag are outside the signature and a frame can be signed, then sealed.
+// PoE scope signs the bundle's poe entry. Either way the signed message is
+// SIG_CONTEXT || scope index || bytes.
+pub mod sig {
+    use super::aux::{AuxBundle, SIG};
+    use super::avrodec::{self, PoE, Sig, SigScope};
+    use super::envelope::{self, DecodedEnvelope, EnvelopeBuilder};
+    use super::Error;
+    use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+    use std::collections::HashMap;
+
+    pub const ALG_ED25519: &str = "Ed25519";
+    pub const SIG_CONTEXT: &[u8] = b"tritrpc-v1 sig\0";
+
+    fn message(scope: SigScope, body: &[u8]) -> Vec<u8> {
+        let mut m = SIG_CONTEXT.to_vec();
+        m.push(scope.index() as u8);
+        m.extend_from_slice(body);
+        m
+    }
+
+    fn bundle_of(builder: &EnvelopeBuilder) -> Result<AuxBundle, Error> {
+        match builder.aux_bytes() {
+            Some(b) => AuxBundle::decode(b),
+            None => Ok(AuxBundle::new()),
+        }
+    }
+
+    // The bytes an Envelope-scope signature covers for a frame built by `builder`.
+    pub fn envelope_signing_bytes(builder: &EnvelopeBuilder) -> Result<Vec<u8>, Error> {
+        let mut bundle = bundle_of(builder)?;
+        bundle.remove(SIG);
+        let aux = (!bundle.is_empty()).then(|| bundle.encode());
+        let b = match aux.as_deref() {
+            Some(a) => builder.aux(a),
+            None => builder.no_aux(),
+        };
+        let mut out = Vec::new();
+        b.write(&mut out);
+        Ok(message(SigScope::Envelope, &out))
+    }
+
+    pub struct Signer {
+        key_id: String,
+        key: SigningKey,
+    }
+
+    impl Signer {
+        pub fn new(key_id: &str, key: SigningKey) -> Self {
+            Signer {
+                key_id: key_id.to_string(),
+                key,
+            }
+        }
+
+        pub fn verifying_key(&self) -> VerifyingKey {
+            self.key.verifying_key()
+        }
+
+        fn sig(&self, scope: SigScope, msg: &[u8]) -> Sig {
+            Sig {
+                alg: ALG_ED25519.to_string(),
+                key_id: self.key_id.clone(),
+                scope,
+                signature: self.key.sign(msg).to_bytes().to_vec(),
+            }
+        }
+
+        // Signs the frame `builder` describes and returns the AUX bytes (its bundle
+        // plus the sig entry) to write it with.
+        pub fn sign_envelope(&self, builder: &EnvelopeBuilder) -> Result<Vec<u8>, Error> {
+            let msg = envelope_signing_bytes(builder)?;
+            let mut bundle = bundle_of(builder)?;
+            bundle.set_sig(&self.sig(SigScope::Envelope, &msg));
+            Ok(bundle.encode())
+        }
+
+        // Adds `poe` and a signature over it to the builder's AUX bundle.
+        pub fn sign_poe(&self, builder: &EnvelopeBuilder, poe: &PoE) -> Result<Vec<u8>, Error> {
+            let mut bundle = bundle_of(builder)?;
+            let body = avrodec::enc_poe(poe);
+            bundle.insert(super::aux::POE, body.clone());
+            bundle.set_sig(&self.sig(SigScope::PoE, &message(SigScope::PoE, &body)));
+            Ok(bundle.encode())
+        }
+    }
+
+    // Trusted signer keys by key ID.
+    #[derive(Debug, Clone, Default)]
+    pub struct Verifier {
+        keys: HashMap<String, VerifyingKey>,
+    }
+
+    impl Verifier {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn insert(&mut self, key_id: &str, key: VerifyingKey) -> Option<VerifyingKey> {
+            self.keys.insert(key_id.to_string(), key)
+        }
+
+        pub fn remove(&mut self, key_id: &str) -> Option<VerifyingKey> {
+            self.keys.remove(key_id)
+        }
+
+        // Checks the frame's Sig entry and returns it. A PoE-scope signature also has
+        // its PoE checked against the frame.
+        pub fn verify(&self, env: &DecodedEnvelope) -> Result<Sig, Error> {
+            let bundle = env
+                .aux_bundle()?
+                .ok_or(Error::MissingField { field: "aux" })?;
+            let sig = bundle.sig()?.ok_or(Error::MissingField { field: "sig" })?;
+            if sig.alg != ALG_ED25519 {
+                return Err(Error::Unsupported {
+                    what: "signature algorithm",
+                });
+            }
+            let key = self
+                .keys
+                .get(&sig.key_id)
+                .ok_or_else(|| Error::UnknownKeyId {
+                    key_id: sig.key_id.clone(),
+                })?;
+            let msg = match sig.scope {
+                SigScope::Envelope => envelope_signing_bytes(&env.to_builder()?)?,
+                SigScope::PoE => {
+                    let poe = bundle.poe()?.ok_or(Error::MissingField { field: "poe" })?;
+                    super::poe::verify(&poe, env)?;
+                    message(SigScope::PoE, &avrodec::enc_poe(&poe))
+                }
+            };
+            let signature =
+                Signature::from_slice(&sig.signature).map_err(|_| Error::BadSignature)?;
+            key.verify_strict(&msg, &signature)
+                .map_err(|_| Error::BadSignature)?;
+            Ok(sig)
+        }
+
+        pub fn decode(&self, frame: &[u8]) -> Result<(DecodedEnvelope, Sig), Error> {
+            let env = envelope::decode(frame)?;
+            let sig = self.verify(&env)?;
+            Ok((env, sig))
+        }
+
+        // open() then verify, for sealed frames.
+        pub fn open(
+            &self,
+            frame: &[u8],
+            key: &[u8; 32],
+            nonce: &[u8; 24],
+        ) -> Result<(DecodedEnvelope, Sig), Error> {
+            let env = envelope::open(frame, key, nonce)?;
+            let sig = self.verify(&env)?;
+            Ok((env, sig))
+        }
+    }
+}
+
+// Symmetric keys by ID with validity windows. Frames sealed through a Keyring carry
+// the key ID in the AUX bundle, so receivers holding both the old and the new key
+// during a rotation pick the right one per frame. Times are Unix seconds.
+pub mod keyring {
+    use super::aux::AuxBundle;
+    use super::envelope::{self, DecodedEnvelope, EnvelopeBuilder};
+    use super::Error;
+    use std::fmt;
+
+    #[derive(Clone, PartialEq, Eq)]
+    pub struct KeyEntry {
+        pub id: String,
+        pub key: [u8; 32],
+        pub not_before: u64,
+        // Exclusive; None = no expiry.
+        pub not_after: Option<u64>,
+    }
+
+    impl KeyEntry {
+        pub fn is_active_at(&self, now: u64) -> bool {
+            self.not_before <= now && !matches!(self.not_after, Some(end) if now >= end)
+        }
+    }
+
+    // Key bytes stay out of logs.
+    impl fmt::Debug for KeyEntry {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("KeyEntry")
+                .field("id", &self.id)
+                .field("not_before", &self.not_before)
+                .field("not_after", &self.not_after)
+                .finish_non_exhaustive()
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct Keyring {
+        keys: Vec<KeyEntry>,
+    }
+
+    impl Keyring {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn insert(&mut self, entry: KeyEntry) -> Result<(), Error> {
+            if self.get(&entry.id).is_some() {
+                return Err(Error::DuplicateKeyId { key_id: entry.id });
+            }
+            self.keys.push(entry);
+            Ok(())
+        }
+
+        pub fn get(&self, key_id: &str) -> Option<&KeyEntry> {
+            self.keys.iter().find(|k| k.id == key_id)
+        }
+
+        pub fn remove(&mut self, key_id: &str) -> Option<KeyEntry> {
+            let i = self.keys.iter().position(|k| k.id == key_id)?;
+            Some(self.keys.remove(i))
+        }
+
+        // The newest key (latest not_before) active at `now`.
+        pub fn signing_key(&self, now: u64) -> Result<&KeyEntry, Error> {
+            self.keys
+                .iter()
+                .filter(|k| k.is_active_at(now))
+                .max_by_key(|k| k.not_before)
+                .ok_or(Error::NoActiveKey)
+        }
+
+        pub fn verification_key(&self, key_id: &str, now: u64) -> Result<&[u8; 32], Error> {
+            let entry = self.get(key_id).ok_or_else(|| Error::UnknownKeyId {
+                key_id: key_id.to_string(),
+            })?;
+            if !entry.is_active_at(now) {
+                return Err(Error::KeyNotActive {
+                    key_id: key_id.to_string(),
+                });
+            }
+            Ok(&entry.key)
+        }
+
+        // Seals with the current signing key, adding its ID to the builder's AUX
+        // bundle (or starting one).
+        pub fn seal(
+            &self,
+            builder: &EnvelopeBuilder,
+            now: u64,
+            nonce: &[u8; 24],
+            out: &mut Vec<u8>,
+        ) -> Result<[u8; 16], Error> {
+            let entry = self.signing_key(now)?;
+            let mut bundle = match builder.aux_bytes() {
+                Some(b) => AuxBundle::decode(b)?,
+                None => AuxBundle::new(),
+            };
+            bundle.set_key_id(&entry.id);
+            let aux = bundle.encode();
+            Ok(builder.aux(&aux).seal(&entry.key, nonce, out))
+        }
+
+        // Reads the key ID from the frame's AUX bundle and opens it with that key.
+        pub fn open(
+            &self,
+            frame: &[u8],
+            nonce: &[u8; 24],
+            now: u64,
+        ) -> Result<DecodedEnvelope, Error> {
+            let env = envelope::decode_ref(frame)?;
+            let aux = env.aux.ok_or(Error::MissingField { field: "aux" })?;
+            let bundle = AuxBundle::decode(aux.bytes)?;
+            let key_id = bundle
+                .key_id()?
+                .ok_or(Error::MissingField { field: "key id" })?;
+            let key = self.verification_key(key_id, now)?;
+            envelope::open(frame, key, nonce)
+        }
+    }
+}
+
+// HELLO/CHOOSE negotiation. The client offers modes, AEAD suites and compression
+// in a Hello frame; the server picks the first entry of each of its own lists that
+// the client also offered and answers with Choose, or with an Error frame when a
+// list has no overlap. Control frames are unsealed Path-A envelopes.
+//
+// With key agreement on, Hello and Choose also carry ephemeral X25519 public keys in
+// their AUX bundles, and both sides derive per-direction keys and base nonces with
+// HKDF-SHA256 salted by a hash of the two frames as sent.
+pub mod handshake {
+    use super::aead::{self, AeadSuite};
+    use super::aux::AuxBundle;
+    use super::avrodec::{self, Choose, ErrorMsg, Hello};
+    use super::envelope::{self, DecodedEnvelope, EnvelopeBuilder, Flags, Mode};
+    use super::Error;
+    use hkdf::Hkdf;
+    use sha2::{Digest, Sha256};
+    use std::fmt;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    pub const SERVICE: &str = "tritrpc.v1";
+    pub const HELLO: &str = "Hello";
+    pub const CHOOSE: &str = "Choose";
+    pub const ERROR: &str = "Error";
+
+    pub const COMPRESSION_NONE: &str = "none";
+
+    // AUX entry holding the sender's 32-byte ephemeral X25519 public key.
+    pub const KEY_SHARE: &str = "x25519";
+
+    // Error.code values sent by the server.
+    pub const ERR_BAD_HELLO: i32 = 1;
+    pub const ERR_NO_COMMON_MODE: i32 = 2;
+    pub const ERR_NO_COMMON_SUITE: i32 = 3;
+    pub const ERR_NO_COMMON_COMPRESSION: i32 = 4;
+    pub const ERR_BAD_KEY_SHARE: i32 = 5;
+
+    // What one side supports, in order of preference.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Policy {
+        pub modes: Vec<Mode>,
+        pub aead_suites: Vec<String>,
+        pub compression: Vec<String>,
+        pub context_uri: Option<String>,
+        // Offer (client) or accept (server) an X25519 key share. A client that offers
+        // one rejects a Choose without one rather than falling back to the static key.
+        pub key_agreement: bool,
+    }
+
+    impl Default for Policy {
+        fn default() -> Self {
+            Policy {
+                modes: vec![Mode::PathA, Mode::PathB],
+                aead_suites: aead::SUITE_IDS.iter().map(|s| s.to_string()).collect(),
+                compression: vec![COMPRESSION_NONE.to_string()],
+                context_uri: None,
+                key_agreement: true,
+            }
+        }
+    }
+
+    impl Policy {
+        pub fn hello(&self) -> Hello {
+            Hello {
+                modes: self.modes.iter().map(|m| m.name().to_string()).collect(),
+                aead_suites: self.aead_suites.clone(),
+                compression: self.compression.clone(),
+                context_uri: self.context_uri.clone(),
+            }
+        }
+    }
+
+    #[derive(Clone, PartialEq, Eq)]
+    pub struct DirectionKeys {
+        pub key: [u8; 32],
+        // Sized for the negotiated suite's nonce.
+        pub base_nonce: Vec<u8>,
+    }
+
+    impl fmt::Debug for DirectionKeys {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("DirectionKeys").finish_non_exhaustive()
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SessionKeys {
+        pub client_to_server: DirectionKeys,
+        pub server_to_client: DirectionKeys,
+    }
+
+    // HKDF-SHA256 over the X25519 shared secret followed by the provisioned static key,
+    // salted with SHA-256 of the Hello frame followed by the Choose frame. The X25519
+    // exchange is unauthenticated; mixing in the static key means only its holders
+    // end up with working keys, and the salt binds them to what both sides saw. Each
+    // direction gets its own key and base nonce.
+    pub fn derive_session_keys(
+        psk: &[u8; 32],
+        shared_secret: &[u8; 32],
+        hello_frame: &[u8],
+        choose_frame: &[u8],
+        nonce_len: usize,
+    ) -> SessionKeys {
+        let transcript = Sha256::new()
+            .chain_update(hello_frame)
+            .chain_update(choose_frame)
+            .finalize();
+        let ikm = [&shared_secret[..], &psk[..]].concat();
+        let hk = Hkdf::<Sha256>::new(Some(&transcript), &ikm);
+        let direction = |label: &str| {
+            let mut key = [0u8; 32];
+            hk.expand(format!("tritrpc v1 {} key", label).as_bytes(), &mut key)
+                .expect("32 bytes is a valid HKDF-SHA256 length");
+            let mut base_nonce = vec![0u8; nonce_len];
+            hk.expand(
+                format!("tritrpc v1 {} nonce", label).as_bytes(),
+                &mut base_nonce,
+            )
+            .expect("nonce length is a valid HKDF-SHA256 length");
+            DirectionKeys { key, base_nonce }
+        };
+        SessionKeys {
+            client_to_server: direction("c2s"),
+            server_to_client: direction("s2c"),
+        }
+    }
+
+    // Negotiated parameters handed to the transport. `keys` is set when both sides
+    // did key agreement; otherwise the transport falls back to provisioned keys.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Session {
+        pub mode: Mode,
+        pub aead_suite: String,
+        pub compression: String,
+        pub context_uri: Option<String>,
+        pub keys: Option<SessionKeys>,
+    }
+
+    impl Session {
+        pub fn suite(&self) -> &'static dyn AeadSuite {
+            aead::suite(&self.aead_suite).expect("negotiated suite is known")
+        }
+
+        pub fn flags(&self) -> Flags {
+            Flags {
+                compress: self.compression != COMPRESSION_NONE,
+            }
+        }
+
+        // A builder preset with the negotiated mode and flags.
+        pub fn builder<'a>(&self, service: &'a str, method: &'a str) -> EnvelopeBuilder<'a> {
+            EnvelopeBuilder::new(service, method)
+                .mode(self.mode)
+                .flags(self.flags())
+        }
+    }
+
+    fn control_frame(method: &str, payload: &[u8], key_share: Option<&PublicKey>) -> Vec<u8> {
+        let aux = key_share.map(|pk| {
+            let mut bundle = AuxBundle::new();
+            bundle.insert(KEY_SHARE, pk.as_bytes().to_vec());
+            bundle.encode()
+        });
+        let mut b = EnvelopeBuilder::new(SERVICE, method).payload(payload);
+        if let Some(aux) = aux.as_deref() {
+            b = b.aux(aux);
+        }
+        b.to_vec()
+    }
+
+    fn key_share(env: &DecodedEnvelope) -> Result<Option<PublicKey>, Error> {
+        let Some(aux) = env.aux.as_deref() else {
+            return Ok(None);
+        };
+        match AuxBundle::decode(aux)?.get(KEY_SHARE) {
+            None => Ok(None),
+            Some(b) => {
+                let pk: [u8; 32] = b.try_into().map_err(|_| Error::BadKeyShare {
+                    reason: "length is not 32",
+                })?;
+                Ok(Some(PublicKey::from(pk)))
+            }
+        }
+    }
+
+    fn agree(secret: EphemeralSecret, peer: &PublicKey) -> Result<[u8; 32], Error> {
+        let shared = secret.diffie_hellman(peer);
+        // a low-order peer key forces a known shared secret
+        if !shared.was_contributory() {
+            return Err(Error::BadKeyShare {
+                reason: "non-contributory",
+            });
+        }
+        Ok(shared.to_bytes())
+    }
+
+    pub fn hello_frame(hello: &Hello) -> Vec<u8> {
+        hello_frame_with_share(hello, None)
+    }
+
+    fn hello_frame_with_share(hello: &Hello, key_share: Option<&PublicKey>) -> Vec<u8> {
+        control_frame(HELLO, &avrodec::enc_hello(hello), key_share)
+    }
+
+    pub fn choose_frame(choose: &Choose) -> Vec<u8> {
+        choose_frame_with_share(choose, None)
+    }
+
+    fn choose_frame_with_share(choose: &Choose, key_share: Option<&PublicKey>) -> Vec<u8> {
+        control_frame(CHOOSE, &avrodec::enc_choose(choose), key_share)
+    }
+
+    pub fn error_frame(err: &ErrorMsg) -> Vec<u8> {
+        control_frame(ERROR, &avrodec::enc_error(err), None)
+    }
+
+    fn control_message(frame: &[u8], method: &str) -> Result<DecodedEnvelope, Error> {
+        let env = envelope::decode(frame)?;
+        if env.service != SERVICE || env.method != method {
+            return Err(Error::UnexpectedMessage {
+                method: format!("{}.{}", env.service, env.method),
+            });
+        }
+        Ok(env)
+    }
+
+    fn pick<'a>(ours: &'a [String], theirs: &[String]) -> Option<&'a String> {
+        ours.iter().find(|s| theirs.contains(s))
+    }
+
+    // Server side of the negotiation: choose from our policy and the peer's Hello.
+    pub fn negotiate(policy: &Policy, hello: &Hello) -> Result<Choose, Error> {
+        let mode = policy
+            .modes
+            .iter()
+            .find(|m| hello.modes.iter().any(|n| n == m.name()))
+            .ok_or(Error::NoCommonParameter { what: "mode" })?;
+        let suite = policy
+            .aead_suites
+            .iter()
+            .filter(|s| aead::suite(s).is_some())
+            .find(|s| hello.aead_suites.contains(s))
+            .ok_or(Error::NoCommonParameter { what: "aead suite" })?;
+        let compression =
+            pick(&policy.compression, &hello.compression).ok_or(Error::NoCommonParameter {
+                what: "compression",
+            })?;
+        Ok(Choose {
+            mode: mode.name().to_string(),
+            aead_suite: suite.clone(),
+            compression: compression.clone(),
+        })
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum ClientState {
+        Start,
+        AwaitChoose,
+        Done,
+    }
+
+    pub struct Client {
+        policy: Policy,
+        psk: [u8; 32],
+        state: ClientState,
+        // Kept between hello() and receive() for key agreement.
+        secret: Option<EphemeralSecret>,
+        hello: Vec<u8>,
+        session: Option<Session>,
+    }
+
+    impl Client {
+        // `psk` is the static key both sides are provisioned with; key agreement mixes
+        // it into the session keys.
+        pub fn new(policy: Policy, psk: [u8; 32]) -> Self {
+            Client {
+                policy,
+                psk,
+                state: ClientState::Start,
+                secret: None,
+                hello: Vec::new(),
+                session: None,
+            }
+        }
+
+        pub fn hello(&mut self) -> Result<Vec<u8>, Error> {
+            if self.state != ClientState::Start {
+                return Err(Error::HandshakeState { what: "hello" });
+            }
+            self.state = ClientState::AwaitChoose;
+            let share = if self.policy.key_agreement {
+                let secret = EphemeralSecret::random();
+                let pk = PublicKey::from(&secret);
+                self.secret = Some(secret);
+                Some(pk)
+            } else {
+                None
+            };
+            self.hello = hello_frame_with_share(&self.policy.hello(), share.as_ref());
+            Ok(self.hello.clone())
+        }
+
+        // Accepts the server's Choose (checked against what we offered) or turns its
+        // Error frame into Error::HandshakeRejected. Either way the handshake is over.
+        pub fn receive(&mut self, frame: &[u8]) -> Result<Session, Error> {
+            if self.state != ClientState::AwaitChoose {
+                return Err(Error::HandshakeState { what: "receive" });
+            }
+            self.state = ClientState::Done;
+            let env = envelope::decode(frame)?;
+            if env.service == SERVICE && env.method == ERROR {
+                let msg = avrodec::dec_error(&env.payload)?;
+                return Err(Error::HandshakeRejected {
+                    code: msg.code,
+                    message: msg.message,
+                });
+            }
+            let env = control_message(frame, CHOOSE)?;
+            let choose = avrodec::dec_choose(&env.payload)?;
+            let mode = Mode::from_name(&choose.mode)
+                .filter(|m| self.policy.modes.contains(m))
+                .ok_or(Error::InvalidChoice { what: "mode" })?;
+            if !self.policy.aead_suites.contains(&choose.aead_suite)
+                || aead::suite(&choose.aead_suite).is_none()
+            {
+                return Err(Error::InvalidChoice { what: "aead suite" });
+            }
+            if !self.policy.compression.contains(&choose.compression) {
+                return Err(Error::InvalidChoice {
+                    what: "compression",
+                });
+            }
+            let keys = match (self.secret.take(), key_share(&env)?) {
+                (Some(secret), Some(peer)) => {
+                    let shared = agree(secret, &peer)?;
+                    let nonce_len = aead::suite(&choose.aead_suite)
+                        .expect("checked above")
+                        .nonce_len();
+                    Some(derive_session_keys(
+                        &self.psk,
+                        &shared,
+                        &self.hello,
+                        frame,
+                        nonce_len,
+                    ))
+                }
+                // Offered but not answered: a stripped share must not downgrade us to
+                // the static key. Answered but not offered is just as invalid.
+                (Some(_), None) | (None, Some(_)) => {
+                    return Err(Error::InvalidChoice { what: "key share" });
+                }
+                (None, None) => None,
+            };
+            let session = Session {
+                mode,
+                aead_suite: choose.aead_suite,
+                compression: choose.compression,
+                context_uri: self.policy.context_uri.clone(),
+                keys,
+            };
+            self.session = Some(session.clone());
+            Ok(session)
+        }
+
+        pub fn session(&self) -> Option<&Session> {
+            self.session.as_ref()
         }
-        tritpack243::pack(&trits)
     }
 
-    pub fn decode_len(bytes: &[u8], mut offset: usize) -> Result<(u64, usize), String> {
-        let mut trits: Vec<u8> = Vec::new();
-        loop {
-            if offset >= bytes.len() {
-                return Err("EOF in TLEB3".into());
+    // The server's answer to a Hello: the frame to send back, and the session or the
+    // reason the handshake failed.
+    #[derive(Debug, Clone)]
+    pub struct Reply {
+        pub frame: Vec<u8>,
+        pub outcome: Result<Session, Error>,
+    }
+
+    #[derive(Clone)]
+    pub struct Server {
+        policy: Policy,
+        psk: [u8; 32],
+        answered: bool,
+        session: Option<Session>,
+    }
+
+    impl fmt::Debug for Server {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Server")
+                .field("policy", &self.policy)
+                .field("answered", &self.answered)
+                .field("session", &self.session)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl Server {
+        pub fn new(policy: Policy, psk: [u8; 32]) -> Self {
+            Server {
+                policy,
+                psk,
+                answered: false,
+                session: None,
             }
-            let b = bytes[offset];
-            offset += 1;
-            let ts = super::tritpack243::unpack(&[b])?;
-            trits.extend_from_slice(&ts);
-            if trits.len() < 3 {
-                continue;
+        }
+
+        pub fn respond(&mut self, frame: &[u8]) -> Result<Reply, Error> {
+            if self.answered {
+                return Err(Error::HandshakeState { what: "respond" });
             }
-            let mut val: u64 = 0;
-            let mut used_trits: usize = 0;
-            for j in 0..(trits.len() / 3) {
-                let c = trits[3 * j] as u64;
-                let p1 = trits[3 * j + 1] as u64;
-                let p0 = trits[3 * j + 2] as u64;
-                let digit = p1 * 3 + p0;
-                val += digit * 9u64.pow(j as u32);
-                if c == 0 {
-                    used_trits = (j + 1) * 3;
-                    break;
+            self.answered = true;
+            let parsed = control_message(frame, HELLO)
+                .and_then(|env| Ok((avrodec::dec_hello(&env.payload)?, key_share(&env)?)));
+            let (hello, peer_share) = match parsed {
+                Ok(h) => h,
+                Err(e @ Error::BadKeyShare { .. }) => return Ok(reject(ERR_BAD_KEY_SHARE, e)),
+                Err(e) => return Ok(reject(ERR_BAD_HELLO, e)),
+            };
+            let choose = match negotiate(&self.policy, &hello) {
+                Ok(c) => c,
+                Err(e) => {
+                    let code = match e {
+                        Error::NoCommonParameter { what: "mode" } => ERR_NO_COMMON_MODE,
+                        Error::NoCommonParameter { what: "aead suite" } => ERR_NO_COMMON_SUITE,
+                        _ => ERR_NO_COMMON_COMPRESSION,
+                    };
+                    return Ok(reject(code, e));
                 }
-            }
-            if used_trits > 0 {
-                let used_bytes = super::tritpack243::pack(&trits[..used_trits]).len();
-                let new_off = offset - 1 + (used_bytes - 1);
-                return Ok((val, new_off));
-            }
+            };
+            let (reply, keys) = match peer_share.filter(|_| self.policy.key_agreement) {
+                Some(peer) => {
+                    let secret = EphemeralSecret::random();
+                    let share = PublicKey::from(&secret);
+                    let shared = match agree(secret, &peer) {
+                        Ok(s) => s,
+                        Err(e) => return Ok(reject(ERR_BAD_KEY_SHARE, e)),
+                    };
+                    let reply = choose_frame_with_share(&choose, Some(&share));
+                    let nonce_len = aead::suite(&choose.aead_suite)
+                        .expect("negotiated suite is known")
+                        .nonce_len();
+                    let keys = derive_session_keys(&self.psk, &shared, frame, &reply, nonce_len);
+                    (reply, Some(keys))
+                }
+                None => (choose_frame(&choose), None),
+            };
+            let session = Session {
+                mode: Mode::from_name(&choose.mode).expect("chosen from our modes"),
+                aead_suite: choose.aead_suite.clone(),
+                compression: choose.compression.clone(),
+                context_uri: hello.context_uri,
+                keys,
+            };
+            self.session = Some(session.clone());
+            Ok(Reply {
+                frame: reply,
+                outcome: Ok(session),
+            })
+        }
+
+        pub fn session(&self) -> Option<&Session> {
+            self.session.as_ref()
         }
     }
-}
 
-pub mod envelope {
-    use super::{tleb3, tritpack243};
-    use chacha20poly1305::aead::{Aead, KeyInit};
-    use chacha20poly1305::XChaCha20Poly1305;
+    fn reject(code: i32, e: Error) -> Reply {
+        Reply {
+            frame: error_frame(&ErrorMsg {
+                code,
+                message: e.to_string(),
+                details: None,
+            }),
+            outcome: Err(e),
+        }
+    }
+}
 
-    const MAGIC_B2: [u8; 2] = [0xF3, 0x2A];
-    pub const SCHEMA_ID_32: [u8; 32] = [
-        0xb2, 0xab, 0x81, 0x45, 0x88, 0xf9, 0x9c, 0x87, 0x5d, 0x37, 0xbb, 0x75, 0x46, 0xd0, 0xdf,
-        0x43, 0x69, 0xc2, 0x8b, 0xc5, 0xf6, 0x0c, 0xe3, 0x8a, 0x66, 0x07, 0xda, 0xc4, 0x68, 0x03,
-        0x43, 0x52,
-    ];
-    pub const CONTEXT_ID_32: [u8; 32] = [
-        0xe6, 0x57, 0x2c, 0x0e, 0x61, 0x8f, 0x18, 0xd5, 0x72, 0xd4, 0xc2, 0x96, 0x9d, 0xb4, 0x90,
-        0x96, 0x59, 0xf0, 0x9e, 0xae, 0xf3, 0x2e, 0xc6, 0x6f, 0xbb, 0x80, 0x4b, 0xad, 0x9d, 0x89,
-        0xaa, 0xcd,
-    ];
+// RFC 8785 JSON Canonicalization Scheme and the BLAKE3 content hash over its output,
+// as required for receipts (see fixtures/jcs_blake3_vectors.json).
+pub mod jcs {
+    use super::Error;
+    use serde_json::{Map, Number, Value};
 
-    fn len_prefix(b: &[u8]) -> Vec<u8> {
-        tleb3::encode_len(b.len() as u64)
+    pub fn canonicalize(value: &Value) -> Result<String, Error> {
+        let mut out = String::new();
+        write_value(value, &mut out)?;
+        Ok(out)
     }
 
-    fn pack_trits(ts: &[u8]) -> Vec<u8> {
-        tritpack243::pack(ts)
+    // Parses `json` (RFC 8259) and canonicalizes it.
+    pub fn canonicalize_str(json: &str) -> Result<String, Error> {
+        let value: Value = serde_json::from_str(json).map_err(|e| Error::Json {
+            message: e.to_string(),
+        })?;
+        canonicalize(&value)
     }
 
-    pub fn flags_trits(aead: bool, compress: bool) -> [u8; 3] {
-        [if aead { 2 } else { 0 }, if compress { 2 } else { 0 }, 0]
+    pub fn content_hash(value: &Value) -> Result<[u8; 32], Error> {
+        Ok(*blake3::hash(canonicalize(value)?.as_bytes()).as_bytes())
     }
 
-    pub fn build(
-        service: &str,
-        method: &str,
-        payload: &[u8],
-        aux: Option<&[u8]>,
-        aead_tag: Option<&[u8]>,
-        aead_on: bool,
-        compress: bool,
-    ) -> Vec<u8> {
-        let mut out: Vec<u8> = Vec::new();
-        out.extend(len_prefix(&MAGIC_B2));
-        out.extend(MAGIC_B2);
-        let ver = pack_trits(&[1]);
-        out.extend(len_prefix(&ver));
-        out.extend(ver);
-        let mode = pack_trits(&[0]);
-        out.extend(len_prefix(&mode));
-        out.extend(mode);
-        let flags = pack_trits(&super::envelope::flags_trits(aead_on, compress));
-        out.extend(len_prefix(&flags));
-        out.extend(flags);
-        let schema = SCHEMA_ID_32;
-        out.extend(len_prefix(&schema));
-        out.extend(&schema);
-        let context = CONTEXT_ID_32;
-        out.extend(len_prefix(&context));
-        out.extend(&context);
-        let svc = service.as_bytes();
-        out.extend(len_prefix(svc));
-        out.extend(svc);
-        let m = method.as_bytes();
-        out.extend(len_prefix(m));
-        out.extend(m);
-        out.extend(len_prefix(payload));
-        out.extend(payload);
-        if let Some(auxb) = aux {
-            out.extend(len_prefix(auxb));
-            out.extend(auxb);
+    fn write_value(value: &Value, out: &mut String) -> Result<(), Error> {
+        match value {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) => out.push_str(&format_number(number_f64(n)?)?),
+            Value::String(s) => write_string(s, out),
+            Value::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_value(item, out)?;
+                }
+                out.push(']');
+            }
+            Value::Object(map) => write_object(map, out)?,
         }
-        if let Some(tag) = aead_tag {
-            out.extend(len_prefix(tag));
-            out.extend(tag);
+        Ok(())
+    }
+
+    // Members sorted by their names as UTF-16 code unit sequences.
+    fn write_object(map: &Map<String, Value>, out: &mut String) -> Result<(), Error> {
+        let mut members: Vec<(Vec<u16>, &String, &Value)> = map
+            .iter()
+            .map(|(k, v)| (k.encode_utf16().collect(), k, v))
+            .collect();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        out.push('{');
+        for (i, (_, k, v)) in members.into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write_string(k, out);
+            out.push(':');
+            write_value(v, out)?;
         }
-        out
+        out.push('}');
+        Ok(())
     }
 
-    pub fn envelope_with_tag(
-        service: &str,
-        method: &str,
-        payload: &[u8],
-        aux: Option<&[u8]>,
-        key: &[u8; 32],
-        nonce: &[u8; 24],
-    ) -> (Vec<u8>, Vec<u8>) {
-        let aad = build(service, method, payload, aux, None, true, false);
-        let aead = XChaCha20Poly1305::new(key.into());
-        let ct = aead
-            .encrypt(
-                nonce.into(),
-                chacha20poly1305::aead::Payload {
-                    msg: b"",
-                    aad: &aad,
-                },
-            )
-            .expect("encrypt");
-        let tag = ct[ct.len() - 16..].to_vec();
-        let frame = build(service, method, payload, aux, Some(&tag), true, false);
-        (frame, tag)
+    fn write_string(s: &str, out: &mut String) {
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\u{08}' => out.push_str("\\b"),
+                '\u{0C}' => out.push_str("\\f"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
     }
 
-    #[derive(Debug, Clone)]
-    pub struct DecodedEnvelope {
-        pub magic: Vec<u8>,
-        pub version: Vec<u8>,
-        pub mode: Vec<u8>,
-        pub flags: Vec<u8>,
-        pub schema: Vec<u8>,
-        pub context: Vec<u8>,
-        pub service: String,
-        pub method: String,
-        pub payload: Vec<u8>,
-        pub aux: Option<Vec<u8>>,
-        pub tag: Option<Vec<u8>>,
-        pub aead_on: bool,
-        pub compress: bool,
-        pub tag_start: Option<usize>,
+    // JSON numbers are IEEE-754 doubles under JCS; integers beyond 2^53 lose precision
+    // exactly as they would in ECMAScript.
+    fn number_f64(n: &Number) -> Result<f64, Error> {
+        n.as_f64().ok_or(Error::NonFiniteNumber)
     }
 
-    fn read_field(frame: &[u8], off: usize) -> Result<(Vec<u8>, usize, usize), String> {
-        let (len, new_off) = tleb3::decode_len(frame, off)?;
-        let l = len as usize;
-        let val_start = new_off;
-        let val_end = val_start + l;
-        if val_end > frame.len() {
-            return Err("field length exceeds frame".into());
-        }
-        Ok((frame[val_start..val_end].to_vec(), val_end, off))
-    }
-
-    pub fn decode(frame: &[u8]) -> Result<DecodedEnvelope, String> {
-        let mut off = 0usize;
-        let (magic, off1, _) = read_field(frame, off)?;
-        off = off1;
-        let (version, off2, _) = read_field(frame, off)?;
-        off = off2;
-        let (mode, off3, _) = read_field(frame, off)?;
-        off = off3;
-        let (flags, off4, _) = read_field(frame, off)?;
-        off = off4;
-        let (schema, off5, _) = read_field(frame, off)?;
-        off = off5;
-        let (context, off6, _) = read_field(frame, off)?;
-        off = off6;
-        let (svc, off7, _) = read_field(frame, off)?;
-        off = off7;
-        let (method, off8, _) = read_field(frame, off)?;
-        off = off8;
-        let (payload, off9, _) = read_field(frame, off)?;
-        off = off9;
-
-        let trits = tritpack243::unpack(&flags)?;
-        let aead_on = trits.get(0) == Some(&2u8);
-        let compress = trits.get(1) == Some(&2u8);
-
-        let mut aux: Option<Vec<u8>> = None;
-        let mut tag: Option<Vec<u8>> = None;
-        let mut tag_start: Option<usize> = None;
-
-        let remaining = frame.len().saturating_sub(off);
-        if remaining > 0 {
-            if aead_on {
-                // If two fields remain, treat as aux + tag. If one remains, tag only.
-                let (first, off10, start10) = read_field(frame, off)?;
-                off = off10;
-                if off < frame.len() {
-                    let (tag_val, off11, start11) = read_field(frame, off)?;
-                    off = off11;
-                    aux = Some(first);
-                    tag = Some(tag_val);
-                    tag_start = Some(start11);
-                } else {
-                    tag = Some(first);
-                    tag_start = Some(start10);
-                }
-            } else {
-                let (aux_val, off10, _) = read_field(frame, off)?;
-                off = off10;
-                aux = Some(aux_val);
-            }
+    // ECMAScript Number::toString for a finite double.
+    pub fn format_number(x: f64) -> Result<String, Error> {
+        if !x.is_finite() {
+            return Err(Error::NonFiniteNumber);
         }
-        if off != frame.len() {
-            return Err("extra bytes after envelope parsing".into());
+        if x == 0.0 {
+            return Ok("0".to_string());
         }
-        Ok(DecodedEnvelope {
-            magic,
-            version,
-            mode,
-            flags,
-            schema,
-            context,
-            service: String::from_utf8(svc).map_err(|_| "service not utf8")?,
-            method: String::from_utf8(method).map_err(|_| "method not utf8")?,
-            payload,
-            aux,
-            tag,
-            aead_on,
-            compress,
-            tag_start,
+        if x < 0.0 {
+            return Ok(format!("-{}", format_number(-x)?));
+        }
+        // Shortest round-trip digits: "d.ddde<exp>" -> digits, and n with x = 0.digits * 10^n
+        let sci = format!("{:e}", x);
+        let (mantissa, exp) = sci.split_once('e').expect("{:e} has an exponent");
+        let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+        let k = digits.len() as i32;
+        let n = exp.parse::<i32>().expect("{:e} exponent is an integer") + 1;
+        Ok(if k <= n && n <= 21 {
+            format!("{}{}", digits, "0".repeat((n - k) as usize))
+        } else if 0 < n && n <= 21 {
+            format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+        } else if -6 < n && n <= 0 {
+            format!("0.{}{}", "0".repeat((-n) as usize), digits)
+        } else {
+            let e = n - 1;
+            let sign = if e < 0 { '-' } else { '+' };
+            if k == 1 {
+                format!("{}e{}{}", digits, sign, e.abs())
+            } else {
+                format!("{}.{}e{}{}", &digits[..1], &digits[1..], sign, e.abs())
+            }
         })
     }
 }
 
+#[allow(non_snake_case)]
 pub mod avroenc {
     // Avro subset encoders: zigzag, varint, string, bytes, array, map, union, enum, records for control+HG
     fn zigzag(n: i64) -> u64 {
@@ -381,6 +3372,19 @@ pub mod avroenc {
         out.push(0);
         out
     }
+    pub fn enc_map_bytes(m: &[(&str, &[u8])]) -> Vec<u8> {
+        if m.is_empty() {
+            return vec![0];
+        }
+        let mut out = Vec::new();
+        out.extend(enc_long(m.len() as i64));
+        for (k, v) in m {
+            out.extend(enc_string(k));
+            out.extend(enc_bytes(v));
+        }
+        out.push(0);
+        out
+    }
     pub fn enc_union(index: i64, payload: Vec<u8>) -> Vec<u8> {
         let mut out = enc_long(index);
         out.extend(payload);
@@ -414,6 +3418,41 @@ pub mod avroenc {
         out.extend(enc_string(comp));
         out
     }
+    pub fn enc_Trace(trace_id: &str, span_id: &str, parent: Option<&str>) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(enc_string(trace_id));
+        out.extend(enc_string(span_id));
+        match parent {
+            None => out.extend(enc_union(0, vec![])),
+            Some(p) => out.extend(enc_union(1, enc_string(p))),
+        }
+        out
+    }
+    pub fn enc_PoE(
+        schema_id_hex: &str,
+        context_id_hex: &str,
+        payload_digest: &[u8],
+        method: &str,
+        timestamp: i64,
+        signer: &str,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(enc_string(schema_id_hex));
+        out.extend(enc_string(context_id_hex));
+        out.extend(enc_bytes(payload_digest));
+        out.extend(enc_string(method));
+        out.extend(enc_long(timestamp));
+        out.extend(enc_string(signer));
+        out
+    }
+    pub fn enc_Sig(alg: &str, key_id: &str, scope: i32, signature: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(enc_string(alg));
+        out.extend(enc_string(key_id));
+        out.extend(enc_enum(scope));
+        out.extend(enc_bytes(signature));
+        out
+    }
     pub fn enc_Error(code: i32, msg: &str, details: Option<&[u8]>) -> Vec<u8> {
         let mut out = Vec::new();
         out.extend(enc_int(code));
@@ -561,18 +3600,22 @@ pub mod avroenc {
 }
 
 pub mod avrodec {
-    use super::avroenc;
+    use super::{avroenc, Error};
 
     fn zigzag_inv(u: u64) -> i64 {
         ((u >> 1) as i64) ^ (-((u & 1) as i64))
     }
 
-    pub fn dec_varint(bytes: &[u8], mut off: usize) -> Result<(u64, usize), String> {
+    pub fn dec_varint(bytes: &[u8], mut off: usize) -> Result<(u64, usize), Error> {
+        let start = off;
         let mut shift = 0u32;
         let mut out = 0u64;
         loop {
             if off >= bytes.len() {
-                return Err("EOF in varint".into());
+                return Err(Error::Eof {
+                    what: "varint",
+                    offset: start,
+                });
             }
             let b = bytes[off];
             off += 1;
@@ -582,68 +3625,74 @@ pub mod avrodec {
             }
             shift += 7;
             if shift > 63 {
-                return Err("varint overflow".into());
+                return Err(Error::VarintOverflow { offset: start });
             }
         }
         Ok((out, off))
     }
 
-    pub fn dec_long(bytes: &[u8], off: usize) -> Result<(i64, usize), String> {
+    pub fn dec_long(bytes: &[u8], off: usize) -> Result<(i64, usize), Error> {
         let (u, new_off) = dec_varint(bytes, off)?;
         Ok((zigzag_inv(u), new_off))
     }
 
-    pub fn dec_int(bytes: &[u8], off: usize) -> Result<(i32, usize), String> {
+    pub fn dec_int(bytes: &[u8], off: usize) -> Result<(i32, usize), Error> {
         let (v, new_off) = dec_long(bytes, off)?;
         Ok((v as i32, new_off))
     }
 
-    pub fn dec_bool(bytes: &[u8], off: usize) -> Result<(bool, usize), String> {
+    pub fn dec_bool(bytes: &[u8], off: usize) -> Result<(bool, usize), Error> {
         if off >= bytes.len() {
-            return Err("EOF in bool".into());
+            return Err(Error::Eof {
+                what: "bool",
+                offset: off,
+            });
         }
         Ok((bytes[off] != 0, off + 1))
     }
 
-    pub fn dec_string(bytes: &[u8], off: usize) -> Result<(String, usize), String> {
-        let (len, mut new_off) = dec_long(bytes, off)?;
+    fn dec_len_prefixed<'a>(
+        bytes: &'a [u8],
+        off: usize,
+        field: &'static str,
+    ) -> Result<(&'a [u8], usize), Error> {
+        let (len, new_off) = dec_long(bytes, off)?;
         if len < 0 {
-            return Err("negative string length".into());
-        }
-        let l = len as usize;
-        let end = new_off + l;
-        if end > bytes.len() {
-            return Err("string length exceeds buffer".into());
+            return Err(Error::NegativeLength { field, offset: off });
         }
-        let s = std::str::from_utf8(&bytes[new_off..end])
-            .map_err(|_| "invalid utf8")?
+        let end = match new_off.checked_add(len as usize) {
+            Some(end) if end <= bytes.len() => end,
+            _ => return Err(Error::FieldOverrun { field, offset: off }),
+        };
+        Ok((&bytes[new_off..end], end))
+    }
+
+    pub fn dec_string(bytes: &[u8], off: usize) -> Result<(String, usize), Error> {
+        let (raw, new_off) = dec_len_prefixed(bytes, off, "string")?;
+        let s = std::str::from_utf8(raw)
+            .map_err(|_| Error::NotUtf8 {
+                field: "string",
+                offset: off,
+            })?
             .to_string();
-        new_off = end;
         Ok((s, new_off))
     }
 
-    pub fn dec_bytes(bytes: &[u8], off: usize) -> Result<(Vec<u8>, usize), String> {
-        let (len, mut new_off) = dec_long(bytes, off)?;
-        if len < 0 {
-            return Err("negative bytes length".into());
-        }
-        let l = len as usize;
-        let end = new_off + l;
-        if end > bytes.len() {
-            return Err("bytes length exceeds buffer".into());
-        }
-        let out = bytes[new_off..end].to_vec();
-        new_off = end;
-        Ok((out, new_off))
+    pub fn dec_bytes(bytes: &[u8], off: usize) -> Result<(Vec<u8>, usize), Error> {
+        let (raw, new_off) = dec_len_prefixed(bytes, off, "bytes")?;
+        Ok((raw.to_vec(), new_off))
     }
 
-    pub fn dec_array_strings(bytes: &[u8], mut off: usize) -> Result<(Vec<String>, usize), String> {
+    pub fn dec_array_strings(bytes: &[u8], off: usize) -> Result<(Vec<String>, usize), Error> {
         let (count, mut new_off) = dec_long(bytes, off)?;
         if count == 0 {
             return Ok((Vec::new(), new_off));
         }
         if count < 0 {
-            return Err("negative array block count".into());
+            return Err(Error::NegativeLength {
+                field: "array block",
+                offset: off,
+            });
         }
         let mut out = Vec::new();
         for _ in 0..count {
@@ -653,21 +3702,27 @@ pub mod avrodec {
         }
         let (end_count, end_off) = dec_long(bytes, new_off)?;
         if end_count != 0 {
-            return Err("non-zero array terminator".into());
+            return Err(Error::NonZeroTerminator {
+                field: "array",
+                offset: new_off,
+            });
         }
         Ok((out, end_off))
     }
 
     pub fn dec_map_strings(
         bytes: &[u8],
-        mut off: usize,
-    ) -> Result<(Vec<(String, String)>, usize), String> {
+        off: usize,
+    ) -> Result<(Vec<(String, String)>, usize), Error> {
         let (count, mut new_off) = dec_long(bytes, off)?;
         if count == 0 {
             return Ok((Vec::new(), new_off));
         }
         if count < 0 {
-            return Err("negative map block count".into());
+            return Err(Error::NegativeLength {
+                field: "map block",
+                offset: off,
+            });
         }
         let mut out = Vec::new();
         for _ in 0..count {
@@ -678,15 +3733,310 @@ pub mod avrodec {
         }
         let (end_count, end_off) = dec_long(bytes, new_off)?;
         if end_count != 0 {
-            return Err("non-zero map terminator".into());
+            return Err(Error::NonZeroTerminator {
+                field: "map",
+                offset: new_off,
+            });
         }
         Ok((out, end_off))
     }
 
-    pub fn dec_union_index(bytes: &[u8], off: usize) -> Result<(i64, usize), String> {
+    pub type BytesMap = Vec<(String, Vec<u8>)>;
+
+    pub fn dec_map_bytes(bytes: &[u8], off: usize) -> Result<(BytesMap, usize), Error> {
+        let mut out = Vec::new();
+        let mut new_off = off;
+        loop {
+            let (count, o) = dec_long(bytes, new_off)?;
+            if count == 0 {
+                return Ok((out, o));
+            }
+            if count < 0 {
+                return Err(Error::NegativeLength {
+                    field: "map block",
+                    offset: new_off,
+                });
+            }
+            new_off = o;
+            for _ in 0..count {
+                let (k, o1) = dec_string(bytes, new_off)?;
+                let (v, o2) = dec_bytes(bytes, o1)?;
+                new_off = o2;
+                out.push((k, v));
+            }
+        }
+    }
+
+    pub fn dec_union_index(bytes: &[u8], off: usize) -> Result<(i64, usize), Error> {
         dec_long(bytes, off)
     }
 
+    // Control
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Hello {
+        pub modes: Vec<String>,
+        pub aead_suites: Vec<String>,
+        pub compression: Vec<String>,
+        pub context_uri: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Choose {
+        pub mode: String,
+        pub aead_suite: String,
+        pub compression: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ErrorMsg {
+        pub code: i32,
+        pub message: String,
+        pub details: Option<Vec<u8>>,
+    }
+
+    fn no_trailing(bytes: &[u8], off: usize, what: &'static str) -> Result<(), Error> {
+        if off != bytes.len() {
+            return Err(Error::TrailingBytes { what, offset: off });
+        }
+        Ok(())
+    }
+
+    pub fn dec_hello(bytes: &[u8]) -> Result<Hello, Error> {
+        let (modes, o1) = dec_array_strings(bytes, 0)?;
+        let (aead_suites, o2) = dec_array_strings(bytes, o1)?;
+        let (compression, o3) = dec_array_strings(bytes, o2)?;
+        let (idx, mut o4) = dec_union_index(bytes, o3)?;
+        let context_uri = if idx == 0 {
+            None
+        } else if idx == 1 {
+            let (s, o5) = dec_string(bytes, o4)?;
+            o4 = o5;
+            Some(s)
+        } else {
+            return Err(Error::InvalidUnionIndex {
+                field: "contextURI",
+                index: idx,
+                offset: o3,
+            });
+        };
+        no_trailing(bytes, o4, "Hello")?;
+        Ok(Hello {
+            modes,
+            aead_suites,
+            compression,
+            context_uri,
+        })
+    }
+
+    pub fn dec_choose(bytes: &[u8]) -> Result<Choose, Error> {
+        let (mode, o1) = dec_string(bytes, 0)?;
+        let (aead_suite, o2) = dec_string(bytes, o1)?;
+        let (compression, o3) = dec_string(bytes, o2)?;
+        no_trailing(bytes, o3, "Choose")?;
+        Ok(Choose {
+            mode,
+            aead_suite,
+            compression,
+        })
+    }
+
+    pub fn dec_error(bytes: &[u8]) -> Result<ErrorMsg, Error> {
+        let (code, o1) = dec_int(bytes, 0)?;
+        let (message, o2) = dec_string(bytes, o1)?;
+        let (idx, mut o3) = dec_union_index(bytes, o2)?;
+        let details = if idx == 0 {
+            None
+        } else if idx == 1 {
+            let (b, o4) = dec_bytes(bytes, o3)?;
+            o3 = o4;
+            Some(b)
+        } else {
+            return Err(Error::InvalidUnionIndex {
+                field: "details",
+                index: idx,
+                offset: o2,
+            });
+        };
+        no_trailing(bytes, o3, "Error")?;
+        Ok(ErrorMsg {
+            code,
+            message,
+            details,
+        })
+    }
+
+    pub fn enc_hello(hello: &Hello) -> Vec<u8> {
+        let modes = hello.modes.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let suites = hello
+            .aead_suites
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>();
+        let comp = hello
+            .compression
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>();
+        avroenc::enc_Hello(&modes, &suites, &comp, hello.context_uri.as_deref())
+    }
+
+    pub fn enc_choose(choose: &Choose) -> Vec<u8> {
+        avroenc::enc_Choose(&choose.mode, &choose.aead_suite, &choose.compression)
+    }
+
+    pub fn enc_error(err: &ErrorMsg) -> Vec<u8> {
+        avroenc::enc_Error(err.code, &err.message, err.details.as_deref())
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Trace {
+        pub trace_id: String,
+        pub span_id: String,
+        pub parent_span_id: Option<String>,
+    }
+
+    pub fn dec_trace(bytes: &[u8], off: usize) -> Result<(Trace, usize), Error> {
+        let (trace_id, o1) = dec_string(bytes, off)?;
+        let (span_id, o2) = dec_string(bytes, o1)?;
+        let (idx, mut o3) = dec_union_index(bytes, o2)?;
+        let parent_span_id = if idx == 0 {
+            None
+        } else if idx == 1 {
+            let (s, o4) = dec_string(bytes, o3)?;
+            o3 = o4;
+            Some(s)
+        } else {
+            return Err(Error::InvalidUnionIndex {
+                field: "parent_span_id",
+                index: idx,
+                offset: o2,
+            });
+        };
+        Ok((
+            Trace {
+                trace_id,
+                span_id,
+                parent_span_id,
+            },
+            o3,
+        ))
+    }
+
+    // A Trace that must fill `bytes` exactly.
+    pub fn dec_trace_record(bytes: &[u8]) -> Result<Trace, Error> {
+        let (trace, off) = dec_trace(bytes, 0)?;
+        no_trailing(bytes, off, "Trace")?;
+        Ok(trace)
+    }
+
+    pub fn enc_trace(trace: &Trace) -> Vec<u8> {
+        avroenc::enc_Trace(
+            &trace.trace_id,
+            &trace.span_id,
+            trace.parent_span_id.as_deref(),
+        )
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct PoE {
+        // Lowercase hex of the envelope's 32-byte IDs.
+        pub schema_id: String,
+        pub context_id: String,
+        pub payload_digest: Vec<u8>,
+        pub method: String,
+        pub timestamp: i64,
+        pub signer: String,
+    }
+
+    pub fn dec_poe(bytes: &[u8], off: usize) -> Result<(PoE, usize), Error> {
+        let (schema_id, o1) = dec_string(bytes, off)?;
+        let (context_id, o2) = dec_string(bytes, o1)?;
+        let (payload_digest, o3) = dec_bytes(bytes, o2)?;
+        let (method, o4) = dec_string(bytes, o3)?;
+        let (timestamp, o5) = dec_long(bytes, o4)?;
+        let (signer, o6) = dec_string(bytes, o5)?;
+        Ok((
+            PoE {
+                schema_id,
+                context_id,
+                payload_digest,
+                method,
+                timestamp,
+                signer,
+            },
+            o6,
+        ))
+    }
+
+    pub fn dec_poe_record(bytes: &[u8]) -> Result<PoE, Error> {
+        let (poe, off) = dec_poe(bytes, 0)?;
+        no_trailing(bytes, off, "PoE")?;
+        Ok(poe)
+    }
+
+    pub fn enc_poe(poe: &PoE) -> Vec<u8> {
+        avroenc::enc_PoE(
+            &poe.schema_id,
+            &poe.context_id,
+            &poe.payload_digest,
+            &poe.method,
+            poe.timestamp,
+            &poe.signer,
+        )
+    }
+
+    // What a Sig covers; Avro enum { Envelope, PoE }.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SigScope {
+        Envelope,
+        PoE,
+    }
+
+    impl SigScope {
+        pub fn index(self) -> i32 {
+            match self {
+                SigScope::Envelope => 0,
+                SigScope::PoE => 1,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Sig {
+        pub alg: String,
+        pub key_id: String,
+        pub scope: SigScope,
+        pub signature: Vec<u8>,
+    }
+
+    pub fn dec_sig_record(bytes: &[u8]) -> Result<Sig, Error> {
+        let (alg, o1) = dec_string(bytes, 0)?;
+        let (key_id, o2) = dec_string(bytes, o1)?;
+        let (idx, o3) = dec_int(bytes, o2)?;
+        let scope = match idx {
+            0 => SigScope::Envelope,
+            1 => SigScope::PoE,
+            _ => {
+                return Err(Error::Unsupported {
+                    what: "signature scope",
+                })
+            }
+        };
+        let (signature, o4) = dec_bytes(bytes, o3)?;
+        no_trailing(bytes, o4, "Sig")?;
+        Ok(Sig {
+            alg,
+            key_id,
+            scope,
+            signature,
+        })
+    }
+
+    pub fn enc_sig(sig: &Sig) -> Vec<u8> {
+        avroenc::enc_Sig(&sig.alg, &sig.key_id, sig.scope.index(), &sig.signature)
+    }
+
+    // Hypergraph
     #[derive(Debug, Clone)]
     pub struct Vertex {
         pub vid: String,
@@ -720,8 +4070,8 @@ pub mod avrodec {
         pub edges: Vec<(String, Vec<String>, Option<i64>)>,
     }
 
-    pub fn dec_vertex(bytes: &[u8], off: usize) -> Result<(Vertex, usize), String> {
-        let (vid, mut o1) = dec_string(bytes, off)?;
+    pub fn dec_vertex(bytes: &[u8], off: usize) -> Result<(Vertex, usize), Error> {
+        let (vid, o1) = dec_string(bytes, off)?;
         let (idx, mut o2) = dec_union_index(bytes, o1)?;
         let label = if idx == 0 {
             None
@@ -730,15 +4080,19 @@ pub mod avrodec {
             o2 = o3;
             Some(s)
         } else {
-            return Err("invalid union index for label".into());
+            return Err(Error::InvalidUnionIndex {
+                field: "label",
+                index: idx,
+                offset: o1,
+            });
         };
         let (attr, o4) = dec_map_strings(bytes, o2)?;
         Ok((Vertex { vid, label, attr }, o4))
     }
 
-    pub fn dec_hyperedge(bytes: &[u8], off: usize) -> Result<(Hyperedge, usize), String> {
-        let (eid, mut o1) = dec_string(bytes, off)?;
-        let (members, mut o2) = dec_array_strings(bytes, o1)?;
+    pub fn dec_hyperedge(bytes: &[u8], off: usize) -> Result<(Hyperedge, usize), Error> {
+        let (eid, o1) = dec_string(bytes, off)?;
+        let (members, o2) = dec_array_strings(bytes, o1)?;
         let (idx, mut o3) = dec_union_index(bytes, o2)?;
         let weight = if idx == 0 {
             None
@@ -747,7 +4101,11 @@ pub mod avrodec {
             o3 = o4;
             Some(w)
         } else {
-            return Err("invalid union index for weight".into());
+            return Err(Error::InvalidUnionIndex {
+                field: "weight",
+                index: idx,
+                offset: o2,
+            });
         };
         let (attr, o5) = dec_map_strings(bytes, o3)?;
         Ok((
@@ -761,8 +4119,8 @@ pub mod avrodec {
         ))
     }
 
-    pub fn dec_hg_request(bytes: &[u8]) -> Result<HGRequest, String> {
-        let (op, mut off) = dec_int(bytes, 0)?;
+    pub fn dec_hg_request(bytes: &[u8]) -> Result<HGRequest, Error> {
+        let (op, off) = dec_int(bytes, 0)?;
         let (idx_v, mut off2) = dec_union_index(bytes, off)?;
         let mut vertex = None;
         if idx_v == 1 {
@@ -799,7 +4157,10 @@ pub mod avrodec {
             off6 = o7;
         }
         if off6 != bytes.len() {
-            return Err("extra bytes after HGRequest".into());
+            return Err(Error::TrailingBytes {
+                what: "HGRequest",
+                offset: off6,
+            });
         }
         Ok(HGRequest {
             op,
@@ -811,19 +4172,29 @@ pub mod avrodec {
         })
     }
 
-    pub fn enc_hg_request(req: &HGRequest) -> Result<Vec<u8>, String> {
+    pub fn enc_hg_request(req: &HGRequest) -> Result<Vec<u8>, Error> {
         match req.op {
             0 => {
-                let v = req.vertex.as_ref().ok_or("missing vertex")?;
+                let v = req
+                    .vertex
+                    .as_ref()
+                    .ok_or(Error::MissingField { field: "vertex" })?;
                 if !v.attr.is_empty() {
-                    return Err("vertex attr not supported in encoder".into());
+                    return Err(Error::Unsupported {
+                        what: "vertex attr in encoder",
+                    });
                 }
                 Ok(avroenc::enc_HGRequest_AddVertex(&v.vid, v.label.as_deref()))
             }
             1 => {
-                let e = req.hyperedge.as_ref().ok_or("missing hyperedge")?;
+                let e = req
+                    .hyperedge
+                    .as_ref()
+                    .ok_or(Error::MissingField { field: "hyperedge" })?;
                 if !e.attr.is_empty() {
-                    return Err("hyperedge attr not supported in encoder".into());
+                    return Err(Error::Unsupported {
+                        what: "hyperedge attr in encoder",
+                    });
                 }
                 let members = e.members.iter().map(|s| s.as_str()).collect::<Vec<_>>();
                 Ok(avroenc::enc_HGRequest_AddHyperedge(
@@ -831,29 +4202,41 @@ pub mod avrodec {
                 ))
             }
             2 => {
-                let vid = req.vid.as_ref().ok_or("missing vid")?;
+                let vid = req
+                    .vid
+                    .as_ref()
+                    .ok_or(Error::MissingField { field: "vid" })?;
                 Ok(avroenc::enc_HGRequest_RemoveVertex(vid))
             }
             3 => {
-                let eid = req.eid.as_ref().ok_or("missing eid")?;
+                let eid = req
+                    .eid
+                    .as_ref()
+                    .ok_or(Error::MissingField { field: "eid" })?;
                 Ok(avroenc::enc_HGRequest_RemoveHyperedge(eid))
             }
             4 => {
-                let vid = req.vid.as_ref().ok_or("missing vid")?;
+                let vid = req
+                    .vid
+                    .as_ref()
+                    .ok_or(Error::MissingField { field: "vid" })?;
                 let k = req.k.unwrap_or(1);
                 Ok(avroenc::enc_HGRequest_QueryNeighbors(vid, k))
             }
             5 => {
-                let vid = req.vid.as_ref().ok_or("missing vid")?;
+                let vid = req
+                    .vid
+                    .as_ref()
+                    .ok_or(Error::MissingField { field: "vid" })?;
                 let k = req.k.unwrap_or(1);
                 Ok(avroenc::enc_HGRequest_GetSubgraph(vid, k))
             }
-            _ => Err("unsupported op".into()),
+            _ => Err(Error::Unsupported { what: "op" }),
         }
     }
 
-    pub fn dec_hg_response(bytes: &[u8]) -> Result<HGResponse, String> {
-        let (ok, mut off) = dec_bool(bytes, 0)?;
+    pub fn dec_hg_response(bytes: &[u8]) -> Result<HGResponse, Error> {
+        let (ok, off) = dec_bool(bytes, 0)?;
         let (idx_err, mut off2) = dec_union_index(bytes, off)?;
         let err = if idx_err == 0 {
             None
@@ -862,12 +4245,19 @@ pub mod avrodec {
             off2 = o3;
             Some(s)
         } else {
-            return Err("invalid union index for err".into());
+            return Err(Error::InvalidUnionIndex {
+                field: "err",
+                index: idx_err,
+                offset: off,
+            });
         };
         let (vcount, mut off3) = dec_long(bytes, off2)?;
         let mut vertices = Vec::new();
         if vcount < 0 {
-            return Err("negative vertices block".into());
+            return Err(Error::NegativeLength {
+                field: "vertices block",
+                offset: off2,
+            });
         }
         if vcount == 0 {
             // ok
@@ -875,21 +4265,29 @@ pub mod avrodec {
             for _ in 0..vcount {
                 let (v, o4) = dec_vertex(bytes, off3)?;
                 if !v.attr.is_empty() {
-                    return Err("vertex attr not supported in response fixtures".into());
+                    return Err(Error::Unsupported {
+                        what: "vertex attr in response",
+                    });
                 }
                 off3 = o4;
                 vertices.push((v.vid, v.label));
             }
             let (endc, o5) = dec_long(bytes, off3)?;
             if endc != 0 {
-                return Err("non-zero vertices terminator".into());
+                return Err(Error::NonZeroTerminator {
+                    field: "vertices",
+                    offset: off3,
+                });
             }
             off3 = o5;
         }
         let (ecount, mut off4) = dec_long(bytes, off3)?;
         let mut edges = Vec::new();
         if ecount < 0 {
-            return Err("negative edges block".into());
+            return Err(Error::NegativeLength {
+                field: "edges block",
+                offset: off3,
+            });
         }
         if ecount == 0 {
             // ok
@@ -897,19 +4295,27 @@ pub mod avrodec {
             for _ in 0..ecount {
                 let (e, o5) = dec_hyperedge(bytes, off4)?;
                 if !e.attr.is_empty() {
-                    return Err("edge attr not supported in response fixtures".into());
+                    return Err(Error::Unsupported {
+                        what: "edge attr in response",
+                    });
                 }
                 off4 = o5;
                 edges.push((e.eid, e.members, e.weight));
             }
             let (endc, o6) = dec_long(bytes, off4)?;
             if endc != 0 {
-                return Err("non-zero edges terminator".into());
+                return Err(Error::NonZeroTerminator {
+                    field: "edges",
+                    offset: off4,
+                });
             }
             off4 = o6;
         }
         if off4 != bytes.len() {
-            return Err("extra bytes after HGResponse".into());
+            return Err(Error::TrailingBytes {
+                what: "HGResponse",
+                offset: off4,
+            });
         }
         Ok(HGResponse {
             ok,
@@ -919,7 +4325,7 @@ pub mod avrodec {
         })
     }
 
-    pub fn enc_hg_response(resp: &HGResponse) -> Result<Vec<u8>, String> {
+    pub fn enc_hg_response(resp: &HGResponse) -> Result<Vec<u8>, Error> {
         let vertices = resp
             .vertices
             .iter()
@@ -943,12 +4349,9 @@ pub mod avrodec {
 }
 
 pub mod tritrpc_v1_tests {
-    use super::envelope;
-    use chacha20poly1305::aead::{Aead, KeyInit};
-    use chacha20poly1305::XChaCha20Poly1305;
+    use super::{envelope, tritpack243};
     use std::collections::HashMap;
     use std::fs;
-    use subtle::ConstantTimeEq;
 
     pub fn verify_file(fx: &str, nonces_path: &str) -> String {
         let key = [0u8; 32];
@@ -969,36 +4372,44 @@ pub mod tritrpc_v1_tests {
                 "context id mismatch {}",
                 name
             );
-            let repacked = envelope::build(
-                &decoded.service,
-                &decoded.method,
-                &decoded.payload,
-                decoded.aux.as_deref(),
-                decoded.tag.as_deref(),
-                decoded.aead_on,
-                decoded.compress,
-            );
-            assert_eq!(repacked, frame, "repack mismatch {}", name);
+            let mode = tritpack243::unpack(&decoded.mode).expect("mode trits")[0];
             if decoded.aead_on {
                 let tag = decoded.tag.as_ref().expect("missing tag");
                 let nonce = nonces.get(&name).expect("nonce missing");
                 assert_eq!(nonce.len(), 24, "nonce size mismatch {}", name);
                 assert_eq!(tag.len(), 16, "tag size mismatch {}", name);
-                let aad_start = decoded.tag_start.expect("tag start missing");
-                let aad = &frame[..aad_start];
-                let aead = XChaCha20Poly1305::new(&key.into());
-                let ct = aead
-                    .encrypt(
-                        nonce.as_slice().into(),
-                        chacha20poly1305::aead::Payload { msg: b"", aad },
-                    )
-                    .unwrap();
-                let computed = &ct[ct.len() - 16..];
-                assert!(
-                    computed.ct_eq(tag.as_slice()).into(),
-                    "tag mismatch {}",
-                    name
+                let nonce: [u8; 24] = nonce.as_slice().try_into().unwrap();
+                let opened = envelope::open(&frame, &key, &nonce)
+                    .unwrap_or_else(|e| panic!("tag mismatch {}: {}", name, e));
+                let mut b = envelope::EnvelopeBuilder::new(&opened.service, &opened.method)
+                    .mode(envelope::Mode::from_trit(mode).expect("known mode"))
+                    .flags(envelope::Flags {
+                        compress: opened.compress,
+                    })
+                    .ids(opened.ids())
+                    .payload(&opened.payload);
+                if let Some(aux) = opened.aux.as_deref() {
+                    b = b.aux(aux);
+                }
+                let mut repacked = Vec::new();
+                if opened.payload_encrypted {
+                    b.seal_encrypted(&key, &nonce, &mut repacked);
+                } else {
+                    b.seal(&key, &nonce, &mut repacked);
+                }
+                assert_eq!(repacked, frame, "repack mismatch {}", name);
+            } else {
+                let repacked = envelope::build_with_mode(
+                    mode,
+                    &decoded.service,
+                    &decoded.method,
+                    &decoded.payload,
+                    decoded.aux.as_deref(),
+                    None,
+                    false,
+                    decoded.compress,
                 );
+                assert_eq!(repacked, frame, "repack mismatch {}", name);
             }
             ok += 1;
         }
@@ -1032,6 +4443,7 @@ pub mod tritrpc_v1_tests {
     }
 }
 
+#[allow(non_snake_case)]
 pub mod avroenc_json {
     use super::avroenc;
     use serde_json::Value;
@@ -1080,9 +4492,10 @@ pub mod avroenc_json {
     pub fn enc_HGResponse_json(v: &Value) -> Vec<u8> {
         let ok = v["ok"].as_bool().unwrap_or(true);
         let err = v.get("err").and_then(|e| e.as_str());
+        let empty = vec![];
         let vertices = v["vertices"]
             .as_array()
-            .unwrap_or(&vec![])
+            .unwrap_or(&empty)
             .iter()
             .map(|x| {
                 (
@@ -1093,7 +4506,7 @@ pub mod avroenc_json {
             .collect::<Vec<_>>();
         let edges = v["edges"]
             .as_array()
-            .unwrap_or(&vec![])
+            .unwrap_or(&empty)
             .iter()
             .map(|x| {
                 let eid = x["eid"].as_str().unwrap();
@@ -1138,7 +4551,7 @@ pub mod pathb {
     }
 
     pub fn enc_string(s: &str) -> Vec<u8> {
-        let mut out = tleb3::encode_len(s.as_bytes().len() as u64);
+        let mut out = tleb3::encode_len(s.len() as u64);
         out.extend(s.as_bytes());
         out
     }
@@ -1177,35 +4590,57 @@ pub mod pathb {
 }
 
 pub mod pathb_dec {
-    use super::{tleb3, tritpack243};
+    use super::{tleb3, Error};
 
-    pub fn dec_len(bytes: &[u8], mut off: usize) -> (usize, usize) {
+    pub fn dec_len(bytes: &[u8], off: usize) -> Result<(usize, usize), Error> {
         // decode TLEB3 length and return (len, new_offset)
-        let (val, new_off) = super::tleb3::decode_len(bytes, off).unwrap();
-        (val as usize, new_off)
+        let (val, new_off) = tleb3::decode_len(bytes, off)?;
+        Ok((val as usize, new_off))
     }
 
-    pub fn dec_string(bytes: &[u8], off: usize) -> (String, usize) {
-        let (l, o2) = dec_len(bytes, off);
-        let s = std::str::from_utf8(&bytes[o2..o2 + l]).unwrap().to_string();
-        (s, o2 + l)
+    pub fn dec_string(bytes: &[u8], off: usize) -> Result<(String, usize), Error> {
+        let (l, o2) = dec_len(bytes, off)?;
+        let end = match o2.checked_add(l) {
+            Some(end) if end <= bytes.len() => end,
+            _ => {
+                return Err(Error::FieldOverrun {
+                    field: "string",
+                    offset: off,
+                })
+            }
+        };
+        let s = std::str::from_utf8(&bytes[o2..end])
+            .map_err(|_| Error::NotUtf8 {
+                field: "string",
+                offset: off,
+            })?
+            .to_string();
+        Ok((s, end))
     }
 
-    pub fn dec_union_index(bytes: &[u8], off: usize) -> (u64, usize) {
-        let (u, o2) = super::tleb3::decode_len(bytes, off).unwrap();
-        (u, o2)
+    pub fn dec_union_index(bytes: &[u8], off: usize) -> Result<(u64, usize), Error> {
+        tleb3::decode_len(bytes, off)
     }
 
-    pub fn dec_vertex(bytes: &[u8], off: usize) -> ((String, Option<String>), usize) {
-        let (vid, o2) = dec_string(bytes, off);
-        let (uix, o3) = dec_union_index(bytes, o2);
+    pub fn dec_vertex(
+        bytes: &[u8],
+        off: usize,
+    ) -> Result<((String, Option<String>), usize), Error> {
+        let (vid, o2) = dec_string(bytes, off)?;
+        let (uix, o3) = dec_union_index(bytes, o2)?;
         let (label, o4) = if uix == 0 {
             (None, o3)
         } else {
-            let (s, p) = dec_string(bytes, o3);
+            let (s, p) = dec_string(bytes, o3)?;
             (Some(s), p)
         };
         // skip attr map (length + entries) — for fixtures attr is empty (0x00)
-        ((vid, label), o4 + 1)
+        if o4 >= bytes.len() {
+            return Err(Error::Eof {
+                what: "attr map",
+                offset: o4,
+            });
+        }
+        Ok(((vid, label), o4 + 1))
     }
 }
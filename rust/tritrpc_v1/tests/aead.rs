@@ -0,0 +1,102 @@
+use tritrpc_v1::aead;
+use tritrpc_v1::{avroenc, envelope, Error};
+
+fn key() -> Vec<u8> {
+    (0u8..32).collect()
+}
+
+#[test]
+fn suites_match_independent_tags() {
+    // empty-plaintext tags from Python `cryptography` over aad = b"tritrpc aad"
+    let nonce12: Vec<u8> = (0u8..12).collect();
+    let cases = [
+        (aead::CHACHA20_POLY1305, "f82d741f1f0eb75f8099c376d7b0c153"),
+        (aead::AES_256_GCM_SIV, "b25865c41dfdd00eff2c13bb4ffdc19b"),
+    ];
+    for (id, tag) in cases {
+        let suite = aead::suite(id).unwrap();
+        assert_eq!(suite.id(), id);
+        let computed = suite.compute_tag(&key(), &nonce12, b"tritrpc aad").unwrap();
+        assert_eq!(hex::encode(&computed), tag, "{}", id);
+        suite
+            .verify_tag(&key(), &nonce12, b"tritrpc aad", &computed)
+            .unwrap();
+    }
+}
+
+#[test]
+fn suite_ids_round_trip_through_hello() {
+    for id in aead::SUITE_IDS {
+        assert_eq!(aead::suite(id).unwrap().id(), *id);
+    }
+    assert!(aead::suite("ROT13").is_none());
+    let hello = avroenc::enc_Hello(&["B2"], aead::SUITE_IDS, &["none"], None);
+    let needle = aead::AES_256_GCM_SIV.as_bytes();
+    assert!(hello.windows(needle.len()).any(|w| w == needle));
+}
+
+#[test]
+fn suite_lengths_are_validated() {
+    let suite = aead::suite(aead::CHACHA20_POLY1305).unwrap();
+    assert_eq!(
+        suite.compute_tag(&key(), &[0u8; 24], b""),
+        Err(Error::SuiteLength {
+            suite: aead::CHACHA20_POLY1305,
+            what: "nonce",
+            len: 24,
+            expected: 12
+        })
+    );
+    assert!(matches!(
+        suite.compute_tag(&key()[..16], &[0u8; 12], b""),
+        Err(Error::SuiteLength { what: "key", .. })
+    ));
+    assert!(matches!(
+        suite.verify_tag(&key(), &[0u8; 12], b"", &[0u8; 8]),
+        Err(Error::SuiteLength { what: "tag", .. })
+    ));
+
+    // nothing is written when the parameters are wrong
+    let mut out = Vec::new();
+    let b = envelope::EnvelopeBuilder::new("svc", "m");
+    assert!(b.seal_with(suite, &key(), &[0u8; 24], &mut out).is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn envelopes_seal_and_open_per_suite() {
+    let b = envelope::EnvelopeBuilder::new("hyper.v1", "Ping").payload(b"p");
+    for id in aead::SUITE_IDS {
+        let suite = aead::suite(id).unwrap();
+        let nonce = vec![7u8; suite.nonce_len()];
+        let mut frame = Vec::new();
+        b.seal_with(suite, &key(), &nonce, &mut frame).unwrap();
+        let opened = envelope::open_with(suite, &frame, &key(), &nonce).unwrap();
+        assert_eq!(opened.payload, b"p");
+        // a frame sealed under one suite does not open under another
+        for other in aead::SUITE_IDS.iter().filter(|o| *o != id) {
+            let other = aead::suite(other).unwrap();
+            let n = vec![7u8; other.nonce_len()];
+            assert_eq!(
+                envelope::open_with(other, &frame, &key(), &n),
+                Err(Error::AuthFailed),
+                "{} opened as {}",
+                id,
+                other.id()
+            );
+        }
+    }
+}
+
+#[test]
+fn blake2b_mac_is_explicit_only() {
+    assert!(!aead::SUITE_IDS.contains(&aead::BLAKE2B_MAC));
+    let mac = aead::suite(aead::BLAKE2B_MAC).unwrap();
+    assert!(!mac.is_aead());
+    assert!(aead::SUITE_IDS
+        .iter()
+        .all(|id| aead::suite(id).unwrap().is_aead()));
+    // hashlib.blake2b(b"tritrpc aad", key=bytes(range(32)), digest_size=16)
+    let tag = mac.compute_tag(&key(), &[0u8; 24], b"tritrpc aad").unwrap();
+    assert_eq!(hex::encode(tag), "2c721c9ba3333521e5b755f48a72590e");
+}
@@ -0,0 +1,255 @@
+use tritrpc_v1::{envelope, tleb3, tritpack243, Error};
+
+#[test]
+fn envelope_ref_borrows_with_ranges() {
+    let key = [1u8; 32];
+    let nonce = [2u8; 24];
+    let (frame, tag) = envelope::envelope_with_tag(
+        "hyper.v1",
+        "GetSubgraph.REQ",
+        b"payload-bytes",
+        Some(b"aux-bytes"),
+        &key,
+        &nonce,
+    );
+    let r = envelope::decode_ref(&frame).unwrap();
+    assert_eq!(r.service_str(), "hyper.v1");
+    assert_eq!(r.method_str(), "GetSubgraph.REQ");
+    assert_eq!(r.payload.bytes, b"payload-bytes");
+    assert_eq!(r.aux.unwrap().bytes, b"aux-bytes");
+    assert_eq!(r.tag.unwrap().bytes, tag.as_slice());
+    // views point into the input rather than copies
+    assert!(std::ptr::eq(r.payload.bytes, &frame[r.payload.range()]));
+
+    // fields tile the frame with no gaps
+    let fields = [
+        r.magic,
+        r.version,
+        r.mode,
+        r.flags,
+        r.schema,
+        r.context,
+        r.service,
+        r.method,
+        r.payload,
+        r.aux.unwrap(),
+        r.tag.unwrap(),
+    ];
+    let mut off = 0;
+    for f in fields.iter() {
+        assert_eq!(f.start, off);
+        off = f.span().end;
+    }
+    assert_eq!(off, frame.len());
+    assert_eq!(r.tag_start(), Some(r.tag.unwrap().start));
+
+    let owned: envelope::DecodedEnvelope = r.into();
+    assert_eq!(owned, envelope::decode(&frame).unwrap());
+}
+
+#[test]
+fn peek_route_stops_before_payload() {
+    let frame = envelope::build(
+        "hyper.v1",
+        "QueryNeighbors.REQ",
+        b"opaque",
+        None,
+        None,
+        false,
+        false,
+    );
+    let route = envelope::peek_route(&frame).unwrap();
+    assert_eq!(route.service, "hyper.v1");
+    assert_eq!(route.method, "QueryNeighbors.REQ");
+    assert_eq!(route.mode, 0);
+    assert!(!route.aead_on);
+    assert_eq!(route.schema, &envelope::SCHEMA_ID_32);
+    let r = envelope::decode_ref(&frame).unwrap();
+    assert_eq!(route.payload_offset, r.payload.start);
+
+    // a garbage payload length does not matter to the peek
+    let mut truncated = frame[..route.payload_offset].to_vec();
+    truncated.push(0xFF);
+    assert_eq!(
+        envelope::peek_route(&truncated).unwrap().method,
+        "QueryNeighbors.REQ"
+    );
+    assert!(envelope::decode(&truncated).is_err());
+}
+
+#[test]
+fn header_checks_agree_across_entry_points() {
+    let frame = envelope::build("s", "m", b"", None, None, false, false);
+    let r = envelope::decode_ref(&frame).unwrap();
+
+    let mut bad_magic = frame.clone();
+    bad_magic[r.magic.value_start] = 0x00;
+    assert_eq!(
+        envelope::peek_route(&bad_magic),
+        Err(Error::BadMagic { offset: 0 })
+    );
+    assert_eq!(
+        envelope::decode(&bad_magic),
+        Err(Error::BadMagic { offset: 0 })
+    );
+
+    let mut bad_version = frame.clone();
+    // version 1 is packed as a one-trit tail group [0xF3, 0x01]
+    bad_version[r.version.value_start + 1] = 0x02;
+    assert_eq!(
+        envelope::peek_route(&bad_version),
+        Err(Error::UnsupportedVersion {
+            offset: r.version.start
+        })
+    );
+    // a gateway and the backend behind it reject the same frames
+    assert_eq!(
+        envelope::decode(&bad_version).map(|_| ()),
+        envelope::peek_route(&bad_version).map(|_| ())
+    );
+}
+
+#[test]
+fn custom_ids_round_trip() {
+    let ids = envelope::EnvelopeIds {
+        schema: [0x11; 32],
+        context: [0x22; 32],
+    };
+    let frame = envelope::build_with_ids(&ids, 0, "svc", "m", b"p", None, None, false, false);
+    let decoded = envelope::decode(&frame).unwrap();
+    assert_eq!(decoded.ids(), ids);
+    let r = envelope::decode_ref(&frame).unwrap();
+    assert_eq!(r.schema_id(), &[0x11; 32]);
+    assert_eq!(envelope::peek_route(&frame).unwrap().context, &[0x22; 32]);
+
+    let default = envelope::build("svc", "m", b"p", None, None, false, false);
+    assert_eq!(
+        envelope::decode(&default).unwrap().ids(),
+        envelope::EnvelopeIds::default()
+    );
+}
+
+#[test]
+fn short_ids_are_rejected() {
+    let frame = envelope::build("svc", "m", b"", None, None, false, false);
+    let r = envelope::decode_ref(&frame).unwrap();
+    // splice a 31-byte schema field in place of the 32-byte one
+    let mut short = frame[..r.schema.start].to_vec();
+    short.extend(tleb3::encode_len(31));
+    short.extend(&r.schema.bytes[..31]);
+    short.extend(&frame[r.schema.end()..]);
+    let want = Error::BadIdLength {
+        field: "schema",
+        len: 31,
+        offset: r.schema.start,
+    };
+    assert_eq!(envelope::decode(&short), Err(want.clone()));
+    assert_eq!(envelope::peek_route(&short), Err(want));
+}
+
+#[test]
+fn builder_matches_positional_build() {
+    let frame = envelope::EnvelopeBuilder::new("hyper.v1", "Ping")
+        .version(envelope::Version::V1)
+        .payload(b"pp")
+        .aux(b"aux")
+        .flags(envelope::Flags { compress: true })
+        .to_vec();
+    assert_eq!(envelope::peek_route(&frame).unwrap().method, "Ping");
+    assert_eq!(
+        frame,
+        envelope::build("hyper.v1", "Ping", b"pp", Some(b"aux"), None, false, true)
+    );
+
+    let key = [3u8; 32];
+    let nonce = [4u8; 24];
+    // seal appends to whatever the caller already has in the buffer
+    let mut out = vec![0xAA];
+    let tag = envelope::EnvelopeBuilder::new("hyper.v1", "Ping")
+        .mode(envelope::Mode::PathB)
+        .payload(b"pp")
+        .seal(&key, &nonce, &mut out);
+    assert_eq!(out[0], 0xAA);
+    let decoded = envelope::decode(&out[1..]).unwrap();
+    assert!(decoded.aead_on);
+    assert_eq!(decoded.tag.as_deref(), Some(&tag[..]));
+    assert_eq!(decoded.aux, None);
+    assert_eq!(decoded.mode, tritpack243::pack(&[1]));
+
+    // mode is part of the AAD
+    let (legacy, legacy_tag) =
+        envelope::envelope_with_tag("hyper.v1", "Ping", b"pp", None, &key, &nonce);
+    assert_ne!(legacy_tag, tag);
+    let mut path_a = Vec::new();
+    envelope::EnvelopeBuilder::new("hyper.v1", "Ping")
+        .payload(b"pp")
+        .seal(&key, &nonce, &mut path_a);
+    assert_eq!(path_a, legacy);
+}
+
+#[test]
+fn open_verifies_the_tag() {
+    let key = [5u8; 32];
+    let nonce = [6u8; 24];
+    let (frame, _) =
+        envelope::envelope_with_tag("hyper.v1", "Ping", b"payload", Some(b"aux"), &key, &nonce);
+    let opened = envelope::open(&frame, &key, &nonce).unwrap();
+    assert_eq!(opened.payload, b"payload");
+
+    let payload = envelope::decode_ref(&frame).unwrap().payload;
+    let mut tampered = frame.clone();
+    tampered[payload.value_start] ^= 1;
+    assert_eq!(
+        envelope::open(&tampered, &key, &nonce),
+        Err(Error::AuthFailed)
+    );
+    assert_eq!(
+        envelope::open(&frame, &key, &[7u8; 24]),
+        Err(Error::AuthFailed)
+    );
+    assert_eq!(
+        envelope::open(&frame, &[0u8; 32], &nonce),
+        Err(Error::AuthFailed)
+    );
+
+    let plain = envelope::build("hyper.v1", "Ping", b"payload", None, None, false, false);
+    assert_eq!(
+        envelope::open(&plain, &key, &nonce),
+        Err(Error::MissingField { field: "tag" })
+    );
+}
+
+#[test]
+fn encrypted_payload_round_trip() {
+    let key = [8u8; 32];
+    let nonce = [9u8; 24];
+    let mut frame = Vec::new();
+    envelope::EnvelopeBuilder::new("hyper.v1", "Secret")
+        .payload(b"attack at dawn")
+        .aux(b"aux")
+        .seal_encrypted(&key, &nonce, &mut frame);
+
+    let route = envelope::peek_route(&frame).unwrap();
+    assert!(route.aead_on && route.payload_encrypted);
+    let r = envelope::decode_ref(&frame).unwrap();
+    assert_eq!(r.payload.bytes.len(), 14);
+    assert_ne!(r.payload.bytes, b"attack at dawn");
+    assert_eq!(r.aux.unwrap().bytes, b"aux");
+
+    let opened = envelope::open(&frame, &key, &nonce).unwrap();
+    assert_eq!(opened.payload, b"attack at dawn");
+
+    // header, aux and ciphertext are all covered
+    for off in [
+        r.method.value_start,
+        r.aux.unwrap().value_start,
+        r.payload.value_start,
+    ] {
+        let mut tampered = frame.clone();
+        tampered[off] ^= 1;
+        assert_eq!(
+            envelope::open(&tampered, &key, &nonce),
+            Err(Error::AuthFailed)
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use tritrpc_v1::{avrodec, envelope, pathb_dec, tleb3, tritpack243, Error};
+
+#[test]
+fn tritpack_errors_carry_offsets() {
+    assert_eq!(
+        tritpack243::unpack(&[0x00, 0xF3]),
+        Err(Error::TruncatedTail { offset: 1 })
+    );
+    assert_eq!(
+        tritpack243::unpack(&[0x00, 0x01, 0xFF]),
+        Err(Error::InvalidPackByte {
+            byte: 0xFF,
+            offset: 2
+        })
+    );
+}
+
+#[test]
+fn tleb3_eof_reports_start() {
+    let mut enc = tleb3::encode_len(123);
+    enc.pop();
+    let mut buf = vec![0xAA, 0xBB];
+    buf.extend(enc);
+    // 123 = three base-9 digits = 9 trits: one full byte, then a 4-trit tail group
+    assert_eq!(
+        tleb3::decode_len(&buf, 2),
+        Err(Error::TruncatedTail { offset: 3 })
+    );
+    assert_eq!(
+        tleb3::decode_len(&buf[..3], 2),
+        Err(Error::TlebEof { offset: 2 })
+    );
+    assert_eq!(tleb3::decode_len(&[], 0), Err(Error::TlebEof { offset: 0 }));
+}
+
+#[test]
+fn envelope_errors_name_the_field() {
+    let frame = envelope::build("svc", "m", b"payload", None, None, false, false);
+    let truncated = &frame[..frame.len() - 3];
+    match envelope::decode(truncated) {
+        Err(Error::FieldOverrun { field, .. }) => assert_eq!(field, "payload"),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let mut bad_svc = envelope::build("s\u{e9}c", "m", b"", None, None, false, false);
+    let pos = bad_svc.windows(2).position(|w| w == [0xC3, 0xA9]).unwrap();
+    bad_svc[pos] = 0xFF;
+    match envelope::decode(&bad_svc) {
+        Err(Error::NotUtf8 { field, .. }) => assert_eq!(field, "service"),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let mut trailing = envelope::build("svc", "m", b"", Some(b"aux"), None, false, false);
+    let end = trailing.len();
+    trailing.extend(tleb3::encode_len(0));
+    assert_eq!(
+        envelope::decode(&trailing).unwrap_err(),
+        Error::TrailingBytes {
+            what: "envelope",
+            offset: end
+        }
+    );
+}
+
+#[test]
+fn avro_errors_are_typed() {
+    let overflow = [0xFFu8; 11];
+    assert_eq!(
+        avrodec::dec_varint(&overflow, 0),
+        Err(Error::VarintOverflow { offset: 0 })
+    );
+    // length 4 but only 2 bytes follow
+    assert_eq!(
+        avrodec::dec_string(&[0x08, b'a', b'b'], 0),
+        Err(Error::FieldOverrun {
+            field: "string",
+            offset: 0
+        })
+    );
+    assert_eq!(
+        avrodec::dec_bytes(&[0x01], 0),
+        Err(Error::NegativeLength {
+            field: "bytes",
+            offset: 0
+        })
+    );
+    let mut choose = tritrpc_v1::avroenc::enc_Choose("Path-A", "XCHACHA20-POLY1305", "none");
+    let end = choose.len();
+    choose.push(0);
+    assert_eq!(
+        avrodec::dec_choose(&choose),
+        Err(Error::TrailingBytes {
+            what: "Choose",
+            offset: end
+        })
+    );
+    // three empty arrays, then union branch 2
+    assert_eq!(
+        avrodec::dec_hello(&[0x00, 0x00, 0x00, 0x04]),
+        Err(Error::InvalidUnionIndex {
+            field: "contextURI",
+            index: 2,
+            offset: 3
+        })
+    );
+    let err = avrodec::dec_hg_request(&[0x00, 0x04]).unwrap_err();
+    assert!(matches!(err, Error::Eof { .. }), "{:?}", err);
+    let msg: Box<dyn std::error::Error> = Box::new(err);
+    assert!(msg.to_string().contains("EOF"));
+}
+
+#[test]
+fn pathb_dec_does_not_panic() {
+    let mut buf = tleb3::encode_len(10);
+    buf.extend(b"abc");
+    assert_eq!(
+        pathb_dec::dec_string(&buf, 0),
+        Err(Error::FieldOverrun {
+            field: "string",
+            offset: 0
+        })
+    );
+    assert!(pathb_dec::dec_len(&[0xF9], 0).is_err());
+}
+
+#[test]
+fn trailing_fields_must_match_flags() {
+    let key = [1u8; 32];
+    let nonce = [2u8; 24];
+    let (sealed, _) = envelope::envelope_with_tag("svc", "m", b"p", Some(b"aux"), &key, &nonce);
+    let r = envelope::decode_ref(&sealed).unwrap();
+    assert_eq!(r.aux.unwrap().bytes, b"aux");
+
+    // aux but the tag was dropped: previously read back as "tag = aux"
+    let no_tag = &sealed[..r.tag.unwrap().start];
+    assert_eq!(
+        envelope::decode(no_tag),
+        Err(Error::MissingField { field: "tag" })
+    );
+
+    // an aux field the flags do not declare
+    let plain = envelope::build("svc", "m", b"p", None, None, false, false);
+    let mut extra = plain.clone();
+    extra.extend(tleb3::encode_len(3));
+    extra.extend(b"aux");
+    assert_eq!(
+        envelope::decode(&extra),
+        Err(Error::TrailingBytes {
+            what: "envelope",
+            offset: plain.len()
+        })
+    );
+
+    let flags = envelope::decode_ref(&plain).unwrap().flags;
+    let mut bad_flags = plain.clone();
+    // [0, 0, 1]: the aux trit must be 0 or 2
+    bad_flags[flags.range()].copy_from_slice(&tritpack243::pack(&[0, 0, 1]));
+    assert_eq!(
+        envelope::decode(&bad_flags),
+        Err(Error::InvalidFlags {
+            offset: flags.start
+        })
+    );
+}
@@ -3,10 +3,14 @@ use chacha20poly1305::XChaCha20Poly1305;
 use std::collections::HashMap;
 use std::fs;
 use subtle::ConstantTimeEq;
-use tritrpc_v1::{avrodec, avroenc, envelope, tleb3, tritpack243};
+use tritrpc_v1::{avrodec, envelope, tleb3, tritpack243};
+
+fn fixture_path(path: &str) -> String {
+    format!("{}/../../{}", env!("CARGO_MANIFEST_DIR"), path)
+}
 
 fn read_pairs(path: &str) -> Vec<(String, Vec<u8>)> {
-    let txt = fs::read_to_string(path).expect("read fixtures");
+    let txt = fs::read_to_string(fixture_path(path)).expect("read fixtures");
     txt.lines()
         .filter(|l| !l.is_empty() && !l.starts_with('#'))
         .map(|l| {
@@ -20,7 +24,7 @@ fn read_pairs(path: &str) -> Vec<(String, Vec<u8>)> {
 }
 
 fn read_nonces(path: &str) -> HashMap<String, Vec<u8>> {
-    let txt = fs::read_to_string(path).expect("read nonces");
+    let txt = fs::read_to_string(fixture_path(path)).expect("read nonces");
     txt.lines()
         .filter(|l| !l.is_empty())
         .map(|l| {
@@ -32,7 +36,7 @@ fn read_nonces(path: &str) -> HashMap<String, Vec<u8>> {
         .collect()
 }
 
-fn split_fields(mut buf: &[u8]) -> Vec<Vec<u8>> {
+fn split_fields(buf: &[u8]) -> Vec<Vec<u8>> {
     let mut off = 0usize;
     let mut fields: Vec<Vec<u8>> = Vec::new();
     while off < buf.len() {
@@ -48,7 +52,7 @@ fn split_fields(mut buf: &[u8]) -> Vec<Vec<u8>> {
 
 fn aead_bit(flags_bytes: &[u8]) -> bool {
     let trits = tritpack243::unpack(flags_bytes).unwrap();
-    trits.get(0) == Some(&2u8)
+    trits.first() == Some(&2u8)
 }
 
 #[test]
@@ -74,6 +78,10 @@ fn verify_all_frames_and_payloads() {
             "fixtures/vectors_hex_pathB.txt",
             "fixtures/vectors_hex_pathB.txt.nonces",
         ),
+        (
+            "fixtures/vectors_hex_unary_aux.txt",
+            "fixtures/vectors_hex_unary_aux.txt.nonces",
+        ),
     ];
     let key = [0u8; 32];
     for (fx, nx) in sets {
@@ -83,6 +91,14 @@ fn verify_all_frames_and_payloads() {
             let fields = split_fields(&frame);
             assert!(fields.len() >= 9, "{}", name);
             let decoded = envelope::decode(&frame).expect("decode envelope");
+            let borrowed = envelope::decode_ref(&frame).expect("decode envelope ref");
+            assert_eq!(borrowed.into_owned(), decoded, "borrowed view {}", name);
+            assert_eq!(
+                &frame[borrowed.payload.range()],
+                decoded.payload.as_slice(),
+                "payload range {}",
+                name
+            );
             assert_eq!(
                 decoded.schema.as_slice(),
                 envelope::SCHEMA_ID_32.as_slice(),
@@ -96,24 +112,31 @@ fn verify_all_frames_and_payloads() {
                 name
             );
 
-            let repacked = envelope::build(
-                &decoded.service,
-                &decoded.method,
-                &decoded.payload,
-                decoded.aux.as_deref(),
-                decoded.tag.as_deref(),
-                decoded.aead_on,
-                decoded.compress,
-            );
-            assert_eq!(repacked, frame, "repack mismatch {}", name);
+            let mode = tritpack243::unpack(&decoded.mode).expect("mode trits")[0];
+            let mut builder = envelope::EnvelopeBuilder::new(&decoded.service, &decoded.method)
+                .mode(envelope::Mode::from_trit(mode).expect("known mode"))
+                .flags(envelope::Flags {
+                    compress: decoded.compress,
+                })
+                .ids(decoded.ids())
+                .payload(&decoded.payload);
+            if let Some(aux) = decoded.aux.as_deref() {
+                builder = builder.aux(aux);
+            }
 
             let flags = &fields[3];
             let has_aead = aead_bit(flags);
-            if has_aead {
+            if !has_aead {
+                assert_eq!(builder.to_vec(), frame, "repack mismatch {}", name);
+            } else {
                 let tag = decoded.tag.as_ref().expect("missing tag");
                 assert_eq!(tag.len(), 16, "tag size mismatch {}", name);
                 let nonce = nonces.get(&name).expect("nonce missing");
                 assert_eq!(nonce.len(), 24, "nonce size mismatch {}", name);
+                let nonce24: [u8; 24] = nonce.as_slice().try_into().unwrap();
+                let mut repacked = Vec::new();
+                builder.seal(&key, &nonce24, &mut repacked);
+                assert_eq!(repacked, frame, "repack mismatch {}", name);
                 let aad_start = decoded.tag_start.expect("tag start missing");
                 let aad = &frame[..aad_start];
                 let strict = std::env::var("STRICT_AEAD").ok().as_deref() == Some("1");
@@ -125,8 +148,14 @@ fn verify_all_frames_and_payloads() {
                     )
                     .unwrap();
                 let computed = &ct[ct.len() - 16..];
-                let matches = computed.ct_eq(tag.as_slice()).into();
+                let matches: bool = computed.ct_eq(tag.as_slice()).into();
                 assert!(matches, "tag mismatch for {}", name);
+                assert_eq!(
+                    envelope::open(&frame, &key, &nonce24).as_ref(),
+                    Ok(&decoded),
+                    "open {}",
+                    name
+                );
                 if strict {
                     assert!(matches, "strict tag mismatch for {}", name);
                 }
@@ -153,3 +182,222 @@ fn verify_all_frames_and_payloads() {
         }
     }
 }
+
+#[test]
+fn encrypted_fixtures_open_to_plaintext() {
+    let key = [0u8; 32];
+    let plain: HashMap<String, Vec<u8>> = read_pairs("fixtures/vectors_hex_unary_rich.txt")
+        .into_iter()
+        .collect();
+    let nonces = read_nonces("fixtures/vectors_hex_encrypted.txt.nonces");
+    let pairs = read_pairs("fixtures/vectors_hex_encrypted.txt");
+    assert_eq!(pairs.len(), plain.len());
+    for (name, frame) in pairs {
+        let sealed = envelope::decode(&frame).expect("decode envelope");
+        assert!(sealed.aead_on && sealed.payload_encrypted, "{}", name);
+        let expected = envelope::decode(&plain[&name]).unwrap().payload;
+        assert_ne!(sealed.payload, expected, "payload in clear {}", name);
+
+        let nonce: [u8; 24] = nonces[&name].as_slice().try_into().unwrap();
+        let opened = envelope::open(&frame, &key, &nonce).expect("open");
+        assert_eq!(opened.payload, expected, "plaintext mismatch {}", name);
+
+        let mut resealed = Vec::new();
+        envelope::EnvelopeBuilder::new(&opened.service, &opened.method)
+            .payload(&opened.payload)
+            .seal_encrypted(&key, &nonce, &mut resealed);
+        assert_eq!(resealed, frame, "reseal mismatch {}", name);
+    }
+}
+
+#[test]
+fn stream_fixture_nonces_follow_stream_nonce() {
+    let key = [0u8; 32];
+    for (fx, nx) in [
+        (
+            "fixtures/vectors_hex.txt",
+            "fixtures/vectors_hex.txt.nonces",
+        ),
+        (
+            "fixtures/vectors_hex_stream_avronested.txt",
+            "fixtures/vectors_hex_stream_avronested.txt.nonces",
+        ),
+    ] {
+        let nonces = read_nonces(nx);
+        let pairs = read_pairs(fx);
+        let base: [u8; 24] = nonces[&pairs[0].0].as_slice().try_into().unwrap();
+        let mut rx = envelope::StreamNonce::new(base);
+        for (name, frame) in &pairs {
+            assert_eq!(
+                &rx.derive(rx.chunk_index()).unwrap()[..],
+                nonces[name].as_slice(),
+                "{}",
+                name
+            );
+            rx.open(frame, &key).expect("open stream chunk");
+        }
+        assert_eq!(rx.chunk_index(), 4);
+    }
+}
+
+#[test]
+fn blake2b_mac_fixtures_verify_only_under_that_suite() {
+    let key = [0u8; 32];
+    let mac = tritrpc_v1::aead::suite(tritrpc_v1::aead::BLAKE2B_MAC).unwrap();
+    let xchacha = tritrpc_v1::aead::suite(tritrpc_v1::aead::XCHACHA20_POLY1305).unwrap();
+    let nonces = read_nonces("fixtures/vectors_hex_unary_rich_blake2b.txt.nonces");
+    for (name, frame) in read_pairs("fixtures/vectors_hex_unary_rich_blake2b.txt") {
+        let nonce = &nonces[&name];
+        let opened = envelope::open_with(mac, &frame, &key, nonce).expect("open BLAKE2b-MAC");
+        assert!(opened.aead_on, "{}", name);
+        assert_eq!(
+            envelope::open_with(xchacha, &frame, &key, nonce),
+            Err(tritrpc_v1::Error::AuthFailed),
+            "{}",
+            name
+        );
+
+        let mut resealed = Vec::new();
+        envelope::EnvelopeBuilder::new(&opened.service, &opened.method)
+            .payload(&opened.payload)
+            .seal_with(mac, &key, nonce, &mut resealed)
+            .unwrap();
+        assert_eq!(resealed, frame, "reseal mismatch {}", name);
+    }
+}
+
+#[test]
+fn control_fixtures_round_trip() {
+    let pairs = read_pairs("fixtures/vectors_hex_control.txt");
+    assert_eq!(pairs.len(), 5);
+    for (name, frame) in pairs {
+        let decoded = envelope::decode(&frame).expect("decode envelope");
+        assert_eq!(decoded.service, tritrpc_v1::handshake::SERVICE, "{}", name);
+        let recoded = match decoded.method.as_str() {
+            "Hello" => avrodec::enc_hello(&avrodec::dec_hello(&decoded.payload).expect("Hello")),
+            "Choose" => {
+                avrodec::enc_choose(&avrodec::dec_choose(&decoded.payload).expect("Choose"))
+            }
+            "Error" => avrodec::enc_error(&avrodec::dec_error(&decoded.payload).expect("Error")),
+            other => panic!("unexpected method {}", other),
+        };
+        assert_eq!(recoded, decoded.payload, "round-trip mismatch {}", name);
+    }
+
+    let full = read_pairs("fixtures/vectors_hex_control.txt")
+        .into_iter()
+        .find(|(n, _)| n == "tritrpc.v1.Hello.full")
+        .unwrap()
+        .1;
+    let hello = avrodec::dec_hello(&envelope::decode(&full).unwrap().payload).unwrap();
+    assert_eq!(hello.modes, ["Path-A", "Path-B"]);
+    assert_eq!(hello.aead_suites.len(), 3);
+    assert_eq!(hello.context_uri.as_deref(), Some("urn:tritrpc:ctx:hyper"));
+    assert_eq!(tritrpc_v1::handshake::hello_frame(&hello), full);
+}
+
+#[test]
+fn aux_fixtures_carry_trace_under_the_tag() {
+    let key = [0u8; 32];
+    let nonces = read_nonces("fixtures/vectors_hex_unary_aux.txt.nonces");
+    for (name, frame) in read_pairs("fixtures/vectors_hex_unary_aux.txt") {
+        let nonce: [u8; 24] = nonces[&name].as_slice().try_into().unwrap();
+        let opened = envelope::open(&frame, &key, &nonce).expect("open");
+        let trace = opened.trace().expect("trace aux").expect("trace present");
+        assert_eq!(
+            trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736",
+            "{}",
+            name
+        );
+        assert_eq!(
+            trace.parent_span_id.is_some(),
+            name.ends_with(".RSP"),
+            "{}",
+            name
+        );
+        assert_eq!(
+            opened.aux.as_deref(),
+            Some(tritrpc_v1::aux::trace_aux(&trace).as_slice())
+        );
+
+        // every AUX byte is authenticated
+        let aux = envelope::decode_ref(&frame).unwrap().aux.unwrap();
+        for off in aux.value_start..aux.end() {
+            let mut tampered = frame.clone();
+            tampered[off] ^= 0x01;
+            assert_eq!(
+                envelope::open(&tampered, &key, &nonce),
+                Err(tritrpc_v1::Error::AuthFailed),
+                "{} aux byte {}",
+                name,
+                off
+            );
+        }
+    }
+}
+
+#[test]
+fn stream_fixture_aux_starts_with_trace() {
+    // OPEN carries a bare Trace, DATA a Trace followed by the chunk index
+    for (name, frame) in read_pairs("fixtures/vectors_hex.txt") {
+        let decoded = envelope::decode(&frame).unwrap();
+        if !name.contains("Stream") || name.ends_with(".CLOSE") {
+            continue;
+        }
+        let aux = decoded.aux.unwrap();
+        let (trace, off) = avrodec::dec_trace(&aux, 0).expect("Trace");
+        assert_eq!(trace.trace_id, "t-01", "{}", name);
+        assert_eq!(avrodec::enc_trace(&trace), aux[..off], "{}", name);
+        if name.ends_with(".OPEN") {
+            assert_eq!(avrodec::dec_trace_record(&aux), Ok(trace));
+        }
+    }
+}
+
+#[test]
+fn malformed_bundle_is_an_error_not_a_legacy_trace() {
+    use tritrpc_v1::{aux, avroenc};
+    let trace = avrodec::Trace {
+        trace_id: "t-01".into(),
+        span_id: "s-01".into(),
+        parent_span_id: None,
+    };
+    let mut trailing = aux::trace_aux(&trace);
+    trailing.push(0);
+    let mut dup = avroenc::enc_long(2);
+    for _ in 0..2 {
+        dup.extend(avroenc::enc_string("trace"));
+        dup.extend(avroenc::enc_bytes(&avrodec::enc_trace(&trace)));
+    }
+    dup.push(0);
+    for bytes in [trailing, dup] {
+        assert!(aux::classify(&bytes).is_err());
+        let frame = envelope::EnvelopeBuilder::new("hyper.v1", "Ping")
+            .aux(&bytes)
+            .to_vec();
+        assert!(envelope::decode(&frame).unwrap().trace().is_err());
+    }
+}
+
+#[test]
+fn stream_fixture_trace_reads_through_envelope() {
+    for (name, frame) in read_pairs("fixtures/vectors_hex.txt") {
+        let decoded = envelope::decode(&frame).unwrap();
+        let trace = decoded.trace().expect("trace");
+        if name.contains("Stream") && !name.ends_with(".CLOSE") {
+            let trace = trace.expect("trace present");
+            assert_eq!(trace.trace_id, "t-01", "{}", name);
+            // OPEN names the client span; DATA frames are children of it
+            if name.ends_with(".OPEN") {
+                assert_eq!(trace.span_id, "s-01");
+                assert_eq!(trace.parent_span_id, None);
+            } else {
+                assert_eq!(trace.span_id, "s-02", "{}", name);
+                assert_eq!(trace.parent_span_id.as_deref(), Some("s-01"), "{}", name);
+            }
+        } else {
+            // CLOSE carries a bare PoE; unary frames have no AUX
+            assert_eq!(trace, None, "{}", name);
+        }
+    }
+}
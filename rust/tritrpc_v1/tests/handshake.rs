@@ -0,0 +1,239 @@
+use tritrpc_v1::aead;
+use tritrpc_v1::avrodec;
+use tritrpc_v1::envelope::{self, EnvelopeBuilder, Mode};
+use tritrpc_v1::handshake::{self, Client, Policy, Server};
+use tritrpc_v1::Error;
+
+const PSK: [u8; 32] = [0x5A; 32];
+
+#[test]
+fn client_and_server_agree_on_server_preference() {
+    let mut client = Client::new(
+        Policy {
+            modes: vec![Mode::PathB, Mode::PathA],
+            aead_suites: vec![
+                aead::CHACHA20_POLY1305.to_string(),
+                aead::XCHACHA20_POLY1305.to_string(),
+            ],
+            context_uri: Some("urn:ctx".to_string()),
+            ..Policy::default()
+        },
+        PSK,
+    );
+    let mut server = Server::new(Policy::default(), PSK);
+
+    let hello = client.hello().unwrap();
+    let route = envelope::peek_route(&hello).unwrap();
+    assert_eq!(
+        (route.service, route.method),
+        (handshake::SERVICE, handshake::HELLO)
+    );
+
+    let reply = server.respond(&hello).unwrap();
+    let ours = reply.outcome.unwrap();
+    assert_eq!(ours.mode, Mode::PathA);
+    assert_eq!(ours.aead_suite, aead::XCHACHA20_POLY1305);
+    assert_eq!(ours.context_uri.as_deref(), Some("urn:ctx"));
+
+    let theirs = client.receive(&reply.frame).unwrap();
+    assert_eq!(theirs, ours);
+    assert_eq!(client.session(), server.session());
+    assert_eq!(theirs.suite().id(), aead::XCHACHA20_POLY1305);
+    assert!(!theirs.flags().compress);
+
+    // key agreement is on by default: per-direction keys sized for the suite
+    let keys = theirs.keys.as_ref().expect("session keys");
+    assert_ne!(keys.client_to_server.key, keys.server_to_client.key);
+    assert_eq!(keys.client_to_server.base_nonce.len(), 24);
+    let nonce: [u8; 24] = keys
+        .client_to_server
+        .base_nonce
+        .as_slice()
+        .try_into()
+        .unwrap();
+    let mut frame = Vec::new();
+    theirs
+        .builder("hyper.v1", "Ping")
+        .seal(&keys.client_to_server.key, &nonce, &mut frame);
+    assert!(envelope::open(&frame, &ours.keys.unwrap().client_to_server.key, &nonce).is_ok());
+
+    // each side runs once
+    assert_eq!(client.hello(), Err(Error::HandshakeState { what: "hello" }));
+    assert!(server.respond(&hello).is_err());
+}
+
+#[test]
+fn no_overlap_produces_error_frame() {
+    let mut client = Client::new(
+        Policy {
+            aead_suites: vec![aead::BLAKE2B_MAC.to_string()],
+            ..Policy::default()
+        },
+        PSK,
+    );
+    let mut server = Server::new(Policy::default(), PSK);
+    let reply = server.respond(&client.hello().unwrap()).unwrap();
+    assert_eq!(
+        reply.outcome,
+        Err(Error::NoCommonParameter { what: "aead suite" })
+    );
+    let env = envelope::decode(&reply.frame).unwrap();
+    assert_eq!(env.method, handshake::ERROR);
+    let msg = avrodec::dec_error(&env.payload).unwrap();
+    assert_eq!(msg.code, handshake::ERR_NO_COMMON_SUITE);
+
+    match client.receive(&reply.frame) {
+        Err(Error::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, handshake::ERR_NO_COMMON_SUITE)
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(client.session(), None);
+}
+
+#[test]
+fn garbage_hello_is_rejected() {
+    let mut server = Server::new(Policy::default(), PSK);
+    let not_hello = envelope::build("hyper.v1", "Ping", b"", None, None, false, false);
+    let reply = server.respond(&not_hello).unwrap();
+    assert!(reply.outcome.is_err());
+    let env = envelope::decode(&reply.frame).unwrap();
+    assert_eq!(
+        avrodec::dec_error(&env.payload).unwrap().code,
+        handshake::ERR_BAD_HELLO
+    );
+}
+
+#[test]
+fn client_rejects_choice_it_did_not_offer() {
+    let mut client = Client::new(
+        Policy {
+            modes: vec![Mode::PathA],
+            ..Policy::default()
+        },
+        PSK,
+    );
+    client.hello().unwrap();
+    let forged = handshake::choose_frame(&avrodec::Choose {
+        mode: Mode::PathB.name().to_string(),
+        aead_suite: aead::XCHACHA20_POLY1305.to_string(),
+        compression: handshake::COMPRESSION_NONE.to_string(),
+    });
+    assert_eq!(
+        client.receive(&forged),
+        Err(Error::InvalidChoice { what: "mode" })
+    );
+}
+
+#[test]
+fn key_agreement_is_per_connection_and_optional() {
+    let run = |client_policy: Policy, server_policy: Policy| {
+        let mut client = Client::new(client_policy, PSK);
+        let mut server = Server::new(server_policy, PSK);
+        let reply = server.respond(&client.hello().unwrap()).unwrap();
+        let session = client.receive(&reply.frame)?;
+        assert_eq!(Some(&session), server.session());
+        Ok(session.keys)
+    };
+    let a = run(Policy::default(), Policy::default()).unwrap().unwrap();
+    let b = run(Policy::default(), Policy::default()).unwrap().unwrap();
+    assert_ne!(a, b);
+
+    let off = Policy {
+        key_agreement: false,
+        ..Policy::default()
+    };
+    assert_eq!(run(off.clone(), Policy::default()), Ok(None));
+    // a client that offered a share does not fall back to the static key
+    assert_eq!(
+        run(Policy::default(), off),
+        Err(Error::InvalidChoice { what: "key share" })
+    );
+}
+
+#[test]
+fn stripped_key_share_is_rejected() {
+    let mut client = Client::new(Policy::default(), PSK);
+    let mut server = Server::new(Policy::default(), PSK);
+    let reply = server.respond(&client.hello().unwrap()).unwrap();
+    // the same Choose, re-encoded without its AUX
+    let choose = envelope::decode(&reply.frame).unwrap();
+    let stripped = EnvelopeBuilder::new(&choose.service, &choose.method)
+        .payload(&choose.payload)
+        .to_vec();
+    assert_eq!(
+        client.receive(&stripped),
+        Err(Error::InvalidChoice { what: "key share" })
+    );
+}
+
+#[test]
+fn session_keys_depend_on_the_provisioned_key() {
+    // An active attacker can run X25519 with each side, but without the provisioned
+    // key it cannot derive the session keys, so the two ends disagree.
+    let mut client = Client::new(Policy::default(), PSK);
+    let mut server = Server::new(Policy::default(), [0xA5; 32]);
+    let reply = server.respond(&client.hello().unwrap()).unwrap();
+    let ours = client.receive(&reply.frame).unwrap().keys.unwrap();
+    let theirs = reply.outcome.unwrap().keys.unwrap();
+    assert_ne!(ours.client_to_server.key, theirs.client_to_server.key);
+    assert_ne!(ours.server_to_client.key, theirs.server_to_client.key);
+}
+
+#[test]
+fn low_order_key_share_is_rejected() {
+    let mut aux = tritrpc_v1::aux::AuxBundle::new();
+    aux.insert(handshake::KEY_SHARE, vec![0u8; 32]);
+    let aux = aux.encode();
+    let hello = tritrpc_v1::avroenc::enc_Hello(
+        &["Path-A"],
+        &[aead::XCHACHA20_POLY1305],
+        &[handshake::COMPRESSION_NONE],
+        None,
+    );
+    let frame = envelope::EnvelopeBuilder::new(handshake::SERVICE, handshake::HELLO)
+        .payload(&hello)
+        .aux(&aux)
+        .to_vec();
+    let reply = Server::new(Policy::default(), PSK).respond(&frame).unwrap();
+    assert!(matches!(reply.outcome, Err(Error::BadKeyShare { .. })));
+    let env = envelope::decode(&reply.frame).unwrap();
+    assert_eq!(
+        avrodec::dec_error(&env.payload).unwrap().code,
+        handshake::ERR_BAD_KEY_SHARE
+    );
+}
+
+#[test]
+fn session_key_derivation_matches_reference() {
+    // HKDF-SHA256 computed independently with Python's hmac/hashlib
+    let shared: [u8; 32] = core::array::from_fn(|i| i as u8);
+    let psk: [u8; 32] = core::array::from_fn(|i| 0x80 + i as u8);
+    let keys = handshake::derive_session_keys(&psk, &shared, b"hello", b"choose", 24);
+    assert_eq!(
+        hex::encode(keys.client_to_server.key),
+        "626abefc690052d87bd41e623f9f7a823d7052ebc6b91477c8e815f0fa59336a"
+    );
+    assert_eq!(
+        hex::encode(&keys.client_to_server.base_nonce),
+        "cafcb3338a16f3af26c37b7c70b526184798b99ecbd49652"
+    );
+    assert_eq!(
+        hex::encode(keys.server_to_client.key),
+        "9583284263e7b675a3887b55ea38254f7ff2c1651275fd956bc7f3f4965e1d72"
+    );
+    assert_eq!(
+        hex::encode(&keys.server_to_client.base_nonce),
+        "4c8bf66e896904e426c501164071f5f7c55b1eaaf40e64d1"
+    );
+    // transcript-bound: a different Choose frame gives different keys
+    assert_ne!(
+        handshake::derive_session_keys(&psk, &shared, b"hello", b"choosE", 24),
+        keys
+    );
+    // and bound to the provisioned key
+    assert_ne!(
+        handshake::derive_session_keys(&[0u8; 32], &shared, b"hello", b"choose", 24),
+        keys
+    );
+}
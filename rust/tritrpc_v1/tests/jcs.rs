@@ -0,0 +1,112 @@
+use serde_json::{json, Value};
+use std::fs;
+use tritrpc_v1::{jcs, Error};
+
+#[test]
+fn jcs_blake3_fixture_vectors() {
+    let path = format!(
+        "{}/../../fixtures/jcs_blake3_vectors.json",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let vectors: Vec<Value> =
+        serde_json::from_str(&fs::read_to_string(path).expect("read vectors")).unwrap();
+    assert!(!vectors.is_empty());
+    for v in vectors {
+        let name = v["name"].as_str().unwrap();
+        let input = v["json_input"].as_str().unwrap();
+        let canonical = jcs::canonicalize_str(input).expect("canonicalize");
+        assert_eq!(canonical, v["canonical_json"].as_str().unwrap(), "{}", name);
+        let value: Value = serde_json::from_str(input).unwrap();
+        assert_eq!(
+            hex::encode(jcs::content_hash(&value).unwrap()),
+            v["blake3_hex"].as_str().unwrap(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn numbers_follow_ecmascript() {
+    // expected strings from Number.prototype.toString in Node
+    for (bits, want) in [
+        (0x0000000000000000u64, "0"),
+        (0x8000000000000000, "0"),
+        (0x0000000000000001, "5e-324"),
+        (0x8000000000000001, "-5e-324"),
+        (0x7fefffffffffffff, "1.7976931348623157e+308"),
+        (0x4340000000000000, "9007199254740992"),
+        (0xc340000000000000, "-9007199254740992"),
+        (0x4430000000000000, "295147905179352830000"),
+        (0x44b52d02c7e14af5, "9.999999999999997e+22"),
+        (0x44b52d02c7e14af6, "1e+23"),
+        (0x44b52d02c7e14af7, "1.0000000000000001e+23"),
+        (0x444b1ae4d6e2ef4e, "999999999999999700000"),
+        (0x444b1ae4d6e2ef50, "1e+21"),
+        (0x3eb0c6f7a0b5ed8c, "9.999999999999997e-7"),
+        (0x3eb0c6f7a0b5ed8d, "0.000001"),
+        (0x41b3de4355555555, "333333333.3333333"),
+        (0x3ff0000000000001, "1.0000000000000002"),
+        (0x4022000000000000, "9"),
+    ] {
+        assert_eq!(
+            jcs::format_number(f64::from_bits(bits)).unwrap(),
+            want,
+            "{:016x}",
+            bits
+        );
+    }
+    assert_eq!(jcs::format_number(f64::NAN), Err(Error::NonFiniteNumber));
+    assert_eq!(
+        jcs::format_number(f64::NEG_INFINITY),
+        Err(Error::NonFiniteNumber)
+    );
+    assert!(matches!(
+        jcs::canonicalize_str("[1e400]"),
+        Err(Error::Json { .. })
+    ));
+    assert_eq!(
+        jcs::canonicalize_str("[1.0, -0, 1E2, 0.5e-6]").unwrap(),
+        "[1,0,100,5e-7]"
+    );
+}
+
+#[test]
+fn members_sort_by_utf16_code_units() {
+    // RFC 8785 section 3.2.3: U+1F600 sorts before U+FB33 as a surrogate pair
+    let v = json!({
+        "\u{20ac}": "Euro Sign",
+        "\r": "Carriage Return",
+        "\u{fb33}": "Hebrew Letter Dalet With Dagesh",
+        "1": "One",
+        "\u{1f600}": "Emoji: Grinning Face",
+        "\u{80}": "Control",
+        "\u{f6}": "Latin Small Letter O With Diaeresis",
+    });
+    let out = jcs::canonicalize(&v).unwrap();
+    let keys: Vec<&str> = [
+        "\"\\r\"",
+        "\"1\"",
+        "\"\u{80}\"",
+        "\"\u{f6}\"",
+        "\"\u{20ac}\"",
+        "\"\u{1f600}\"",
+        "\"\u{fb33}\"",
+    ]
+    .to_vec();
+    let mut last = 0;
+    for k in keys {
+        let at = out.find(&format!("{}:", k)).unwrap();
+        assert!(at >= last, "{} out of order in {}", k, out);
+        last = at;
+    }
+}
+
+#[test]
+fn strings_escape_only_what_jcs_requires() {
+    let v = json!(["\u{0}\u{1f}\u{7f} é/\"\\\u{8}\u{c}\n\r\t", {"b": [], "a": {}}, null, true]);
+    assert_eq!(
+        jcs::canonicalize(&v).unwrap(),
+        "[\"\\u0000\\u001f\u{7f} é/\\\"\\\\\\b\\f\\n\\r\\t\",{\"a\":{},\"b\":[]},null,true]"
+    );
+}
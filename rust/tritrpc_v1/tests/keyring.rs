@@ -0,0 +1,138 @@
+use tritrpc_v1::aux::AuxBundle;
+use tritrpc_v1::envelope::{self, EnvelopeBuilder};
+use tritrpc_v1::keyring::{KeyEntry, Keyring};
+use tritrpc_v1::replay::NonceCache;
+use tritrpc_v1::Error;
+
+fn entry(id: &str, byte: u8, not_before: u64, not_after: Option<u64>) -> KeyEntry {
+    KeyEntry {
+        id: id.to_string(),
+        key: [byte; 32],
+        not_before,
+        not_after,
+    }
+}
+
+#[test]
+fn aux_bundle_round_trips_in_name_order() {
+    let mut bundle = AuxBundle::new();
+    bundle.insert("trace", b"t-01".to_vec());
+    bundle.set_key_id("k1");
+    let bytes = bundle.encode();
+    let decoded = AuxBundle::decode(&bytes).unwrap();
+    assert_eq!(decoded, bundle);
+    assert_eq!(decoded.key_id(), Ok(Some("k1")));
+    let names: Vec<&str> = decoded.iter().map(|(k, _)| k).collect();
+    assert_eq!(names, ["kid", "trace"]);
+    assert_eq!(AuxBundle::new().encode(), vec![0]);
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(AuxBundle::decode(&trailing).is_err());
+}
+
+#[test]
+fn rotation_overlap_opens_frames_from_both_keys() {
+    let mut ring = Keyring::new();
+    ring.insert(entry("k1", 1, 0, Some(200))).unwrap();
+    ring.insert(entry("k2", 2, 100, None)).unwrap();
+    assert_eq!(
+        ring.insert(entry("k2", 3, 0, None)),
+        Err(Error::DuplicateKeyId {
+            key_id: "k2".into()
+        })
+    );
+
+    let nonce = [5u8; 24];
+    let b = EnvelopeBuilder::new("hyper.v1", "Ping").payload(b"p");
+    let mut old = Vec::new();
+    ring.seal(&b, 50, &nonce, &mut old).unwrap();
+    let mut new = Vec::new();
+    ring.seal(&b, 150, &nonce, &mut new).unwrap();
+
+    let kid = |f: &[u8]| {
+        let aux = envelope::decode(f).unwrap().aux.unwrap();
+        AuxBundle::decode(&aux)
+            .unwrap()
+            .key_id()
+            .unwrap()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(kid(&old), "k1");
+    assert_eq!(kid(&new), "k2");
+
+    // inside the overlap both verify
+    assert_eq!(ring.open(&old, &nonce, 150).unwrap().payload, b"p");
+    assert_eq!(ring.open(&new, &nonce, 150).unwrap().payload, b"p");
+    // the frame really is under k2
+    assert!(envelope::open(&new, &[2u8; 32], &nonce).is_ok());
+
+    // after k1 expires its frames are refused
+    assert_eq!(
+        ring.open(&old, &nonce, 200),
+        Err(Error::KeyNotActive {
+            key_id: "k1".into()
+        })
+    );
+    ring.remove("k2");
+    assert_eq!(
+        ring.open(&new, &nonce, 150),
+        Err(Error::UnknownKeyId {
+            key_id: "k2".into()
+        })
+    );
+    assert_eq!(ring.signing_key(300).unwrap_err(), Error::NoActiveKey);
+}
+
+#[test]
+fn seal_keeps_existing_aux_entries() {
+    let mut ring = Keyring::new();
+    ring.insert(entry("k1", 1, 0, None)).unwrap();
+    let mut bundle = AuxBundle::new();
+    bundle.insert("trace", b"t-01".to_vec());
+    let aux = bundle.encode();
+    let nonce = [7u8; 24];
+    let mut frame = Vec::new();
+    ring.seal(
+        &EnvelopeBuilder::new("hyper.v1", "Ping").aux(&aux),
+        0,
+        &nonce,
+        &mut frame,
+    )
+    .unwrap();
+    let opened = ring.open(&frame, &nonce, 0).unwrap();
+    let got = AuxBundle::decode(opened.aux.as_deref().unwrap()).unwrap();
+    assert_eq!(got.get("trace"), Some(&b"t-01"[..]));
+    assert_eq!(got.key_id(), Ok(Some("k1")));
+
+    // frames without a key id cannot be routed to a key
+    let (bare, _) = envelope::envelope_with_tag("hyper.v1", "Ping", b"", None, &[1; 32], &nonce);
+    assert_eq!(
+        ring.open(&bare, &nonce, 0),
+        Err(Error::MissingField { field: "aux" })
+    );
+}
+
+#[test]
+fn replay_guard_wraps_keyring_open() {
+    let mut ring = Keyring::new();
+    ring.insert(entry("k1", 1, 0, None)).unwrap();
+    let nonce = [6u8; 24];
+    let mut frame = Vec::new();
+    ring.seal(
+        &EnvelopeBuilder::new("hyper.v1", "Ping"),
+        10,
+        &nonce,
+        &mut frame,
+    )
+    .unwrap();
+    let mut cache = NonceCache::new(16);
+    cache
+        .guard(&nonce, || ring.open(&frame, &nonce, 10))
+        .unwrap();
+    assert_eq!(
+        cache.guard(&nonce, || ring.open(&frame, &nonce, 10)),
+        Err(Error::Replayed)
+    );
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use tritrpc_v1::aux::AuxBundle;
+use tritrpc_v1::avrodec::{self, PoE};
+use tritrpc_v1::envelope::{self, EnvelopeBuilder};
+use tritrpc_v1::{poe, Error};
+
+fn fixture_frame(path: &str, name: &str) -> Vec<u8> {
+    let txt = fs::read_to_string(format!("{}/../../{}", env!("CARGO_MANIFEST_DIR"), path))
+        .expect("read fixtures");
+    let line = txt
+        .lines()
+        .find(|l| l.starts_with(&format!("{} ", name)))
+        .expect("fixture present");
+    hex::decode(line.split_once(' ').unwrap().1).unwrap()
+}
+
+#[test]
+fn blake3_payload_digest() {
+    assert_eq!(
+        hex::encode(poe::payload_digest(b"")),
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    );
+    assert_eq!(
+        hex::encode(poe::payload_digest(b"abc")),
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    );
+}
+
+#[test]
+fn stream_close_fixture_poe_round_trips() {
+    let frame = fixture_frame(
+        "fixtures/vectors_hex.txt",
+        "hyper.v1.GetSubgraphStream.CLOSE",
+    );
+    let aux = envelope::decode(&frame).unwrap().aux.unwrap();
+    let poe = avrodec::dec_poe_record(&aux).expect("PoE");
+    assert_eq!(poe.schema_id, hex::encode(envelope::SCHEMA_ID_32));
+    assert_eq!(poe.context_id, hex::encode(envelope::CONTEXT_ID_32));
+    assert_eq!(poe.method, "erase-iso");
+    assert_eq!(poe.signer, "validator");
+    assert_eq!(avrodec::enc_poe(&poe), aux);
+}
+
+#[test]
+fn issued_poe_verifies_against_its_frame_only() {
+    let frame = fixture_frame(
+        "fixtures/vectors_hex_unary_rich.txt",
+        "hyper.v1.AddVertex_a.REQ",
+    );
+    let env = envelope::decode(&frame).unwrap();
+    let poe = poe::issue(&env, 1_700_000_000, "validator");
+    assert_eq!(poe.payload_digest, poe::payload_digest(&env.payload));
+    assert_eq!(poe::verify(&poe, &env), Ok(()));
+
+    let mut other = env.clone();
+    other.payload.push(0);
+    assert_eq!(
+        poe::verify(&poe, &other),
+        Err(Error::PoEMismatch {
+            field: "payload_digest"
+        })
+    );
+    let wrong_method = PoE {
+        method: "AddVertex_a.RSP".into(),
+        ..poe.clone()
+    };
+    assert_eq!(
+        poe::verify(&wrong_method, &env),
+        Err(Error::PoEMismatch { field: "method" })
+    );
+    let mut moved = env.clone();
+    moved.context = [0x22; 32];
+    assert_eq!(
+        poe::verify(&poe, &moved),
+        Err(Error::PoEMismatch {
+            field: "context_id"
+        })
+    );
+}
+
+#[test]
+fn poe_rides_in_the_aux_bundle() {
+    let key = [0u8; 32];
+    let nonce = [3u8; 24];
+    let mut sealed = Vec::new();
+    EnvelopeBuilder::new("hyper.v1", "Secret")
+        .payload(b"plaintext")
+        .seal_encrypted(&key, &nonce, &mut sealed);
+    let opened = envelope::open(&sealed, &key, &nonce).unwrap();
+    // digests cover the plaintext, not the ciphertext on the wire
+    let poe = poe::issue(&opened, 42, "node-1");
+    assert_eq!(poe::verify(&poe, &opened), Ok(()));
+    assert!(poe::verify(&poe, &envelope::decode(&sealed).unwrap()).is_err());
+
+    let mut bundle = AuxBundle::new();
+    bundle.set_poe(&poe);
+    let aux = bundle.encode();
+    let close = EnvelopeBuilder::new("hyper.v1", "Secret.CLOSE")
+        .aux(&aux)
+        .to_vec();
+    let got = envelope::decode(&close)
+        .unwrap()
+        .aux_bundle()
+        .unwrap()
+        .unwrap();
+    assert_eq!(got.poe(), Ok(Some(poe)));
+}
@@ -0,0 +1,148 @@
+use tritrpc_v1::aead;
+use tritrpc_v1::envelope::{self, EnvelopeBuilder, StreamNonce};
+use tritrpc_v1::replay::{NonceCache, ReplayWindow, WINDOW};
+use tritrpc_v1::Error;
+
+#[test]
+fn window_accepts_each_counter_once() {
+    let mut w = ReplayWindow::new();
+    for c in [5u64, 7, 6, 100] {
+        w.check(c).unwrap();
+        w.commit(c);
+    }
+    assert_eq!(w.check(7), Err(Error::Replayed));
+    assert_eq!(w.check(100), Err(Error::Replayed));
+    // inside the window but never seen
+    assert_eq!(w.check(99), Ok(()));
+    assert_eq!(w.check(100 - (WINDOW - 1)), Ok(()));
+    // too old to tell: treated as a replay
+    assert_eq!(w.check(100 - WINDOW), Err(Error::Replayed));
+    assert_eq!(w.check(6), Err(Error::Replayed));
+    w.commit(100 + 2 * WINDOW);
+    assert_eq!(w.check(100), Err(Error::Replayed));
+    assert_eq!(w.check(100 + 2 * WINDOW - 1), Ok(()));
+}
+
+#[test]
+fn window_commit_ignores_counters_behind_it() {
+    let mut w = ReplayWindow::new();
+    w.commit(100);
+    w.commit(1);
+    w.commit(100 - WINDOW);
+    assert_eq!(w.check(1), Err(Error::Replayed));
+    // the window itself is unchanged
+    assert_eq!(w.check(100), Err(Error::Replayed));
+    assert_eq!(w.check(100 - (WINDOW - 1)), Ok(()));
+}
+
+#[test]
+fn window_guards_stream_open() {
+    let key = [1u8; 32];
+    let mut tx = StreamNonce::new([9u8; 24]);
+    let mut sent = Vec::new();
+    for method in ["S.OPEN", "S.DATA", "S.CLOSE"] {
+        let nonce = tx.next_nonce().unwrap();
+        let mut frame = Vec::new();
+        EnvelopeBuilder::new("svc", method).seal(&key, &nonce, &mut frame);
+        sent.push((frame, nonce));
+    }
+    let mut w = ReplayWindow::new();
+    // reordering within the window is fine
+    for i in [1, 0, 2] {
+        w.open(&sent[i].0, &key, &sent[i].1).unwrap();
+    }
+    assert_eq!(w.open(&sent[1].0, &key, &sent[1].1), Err(Error::Replayed));
+
+    // a forged frame does not consume its counter
+    let mut w = ReplayWindow::new();
+    let (frame, nonce) = &sent[0];
+    let mut forged = frame.clone();
+    let last = forged.len() - 1;
+    forged[last] ^= 1;
+    assert_eq!(w.open(&forged, &key, nonce), Err(Error::AuthFailed));
+    w.open(frame, &key, nonce).unwrap();
+}
+
+#[test]
+fn nonce_cache_rejects_duplicates_and_is_bounded() {
+    let key = [2u8; 32];
+    let frames: Vec<_> = (0u8..3)
+        .map(|i| {
+            let nonce = [i; 24];
+            let (frame, _) = envelope::envelope_with_tag("svc", "m.REQ", &[i], None, &key, &nonce);
+            (frame, nonce)
+        })
+        .collect();
+    let mut cache = NonceCache::new(2);
+    cache.open(&frames[0].0, &key, &frames[0].1).unwrap();
+    assert_eq!(
+        cache.open(&frames[0].0, &key, &frames[0].1),
+        Err(Error::Replayed)
+    );
+    cache.open(&frames[1].0, &key, &frames[1].1).unwrap();
+    cache.open(&frames[2].0, &key, &frames[2].1).unwrap();
+    assert_eq!(cache.len(), 2);
+    // the oldest nonce has been evicted
+    assert_eq!(cache.check(&frames[0].1), Ok(()));
+    assert_eq!(cache.check(&frames[2].1), Err(Error::Replayed));
+}
+
+#[test]
+fn guards_take_any_verifier_and_nonce_length() {
+    // a 12-byte nonce, as ChaCha20-Poly1305 and AES-256-GCM-SIV use
+    let mut nonce = [0u8; 12];
+    nonce[11] = 5;
+    let mut w = ReplayWindow::new();
+    assert_eq!(w.guard(&nonce, || Ok(1)), Ok(1));
+    assert_eq!(w.guard(&nonce, || Ok(2)), Err(Error::Replayed));
+    // a failed verification does not consume the counter
+    nonce[11] = 6;
+    assert_eq!(
+        w.guard(&nonce, || Err::<(), _>(Error::AuthFailed)),
+        Err(Error::AuthFailed)
+    );
+    assert_eq!(w.guard(&nonce, || Ok(())), Ok(()));
+    assert!(w.guard(&[0u8; 3], || Ok(())).is_err());
+
+    let mut cache = NonceCache::new(4);
+    cache.guard(&nonce, || Ok(())).unwrap();
+    assert_eq!(cache.guard(&nonce, || Ok(())), Err(Error::Replayed));
+}
+
+#[test]
+fn nonce_cache_capacity_is_at_least_one() {
+    let mut cache = NonceCache::new(0);
+    cache.guard(b"n", || Ok(())).unwrap();
+    assert_eq!(cache.check(b"n"), Err(Error::Replayed));
+}
+
+#[test]
+fn guards_open_frames_under_negotiated_suites() {
+    let key = [3u8; 32];
+    for id in [aead::CHACHA20_POLY1305, aead::AES_256_GCM_SIV] {
+        let suite = aead::suite(id).unwrap();
+        let mut nonce = [7u8; 12];
+        nonce[8..].copy_from_slice(&9u32.to_be_bytes());
+        let mut frame = Vec::new();
+        EnvelopeBuilder::new("svc", "S.DATA")
+            .seal_with(suite, &key, &nonce, &mut frame)
+            .unwrap();
+
+        let mut w = ReplayWindow::new();
+        w.open_with(suite, &frame, &key, &nonce).unwrap();
+        assert_eq!(
+            w.open_with(suite, &frame, &key, &nonce),
+            Err(Error::Replayed),
+            "{}",
+            id
+        );
+        let mut cache = NonceCache::new(8);
+        cache.open_with(suite, &frame, &key, &nonce).unwrap();
+        assert_eq!(
+            cache.open_with(suite, &frame, &key, &nonce),
+            Err(Error::Replayed),
+            "{}",
+            id
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use tritrpc_v1::envelope::{self, StreamDecoder};
+use tritrpc_v1::tleb3::{self, LenDecoder, Progress};
+use tritrpc_v1::Error;
+
+fn sample_frames() -> Vec<Vec<u8>> {
+    let key = [7u8; 32];
+    let nonce = [9u8; 24];
+    let (sealed, _) = envelope::envelope_with_tag(
+        "hyper.v1",
+        "AddVertex_a.REQ",
+        b"\x00\x02",
+        None,
+        &key,
+        &nonce,
+    );
+    vec![
+        sealed,
+        envelope::build("hyper.v1", "Ping", b"", None, None, false, false),
+        envelope::build(
+            "hyper.v1",
+            "Big",
+            &[0x55; 700],
+            Some(b"aux"),
+            None,
+            false,
+            false,
+        ),
+    ]
+}
+
+#[test]
+fn len_decoder_matches_decode_len() {
+    for &n in [0u64, 8, 9, 80, 81, 65535, u64::MAX].iter() {
+        let enc = tleb3::encode_len(n);
+        let mut dec = LenDecoder::new();
+        for (i, &b) in enc.iter().enumerate() {
+            let p = dec.push(b).unwrap();
+            if i + 1 < enc.len() {
+                assert_eq!(p, Progress::NeedMore(1));
+            } else {
+                assert_eq!(p, Progress::Complete(n));
+            }
+        }
+        assert!(dec.is_idle());
+    }
+    let mut dec = LenDecoder::new();
+    assert!(dec.push(0xFF).is_err());
+}
+
+#[test]
+fn stream_decoder_handles_any_chunking() {
+    let frames = sample_frames();
+    let wire = frames.concat();
+    for chunk in [1usize, 2, 3, 7, 64, wire.len()] {
+        let mut dec = StreamDecoder::new();
+        let mut out = Vec::new();
+        for piece in wire.chunks(chunk) {
+            dec.feed(piece);
+            while let Progress::Complete(env) = dec.next_frame().unwrap() {
+                out.push(env);
+            }
+        }
+        assert_eq!(out.len(), frames.len(), "chunk size {}", chunk);
+        for (env, f) in out.iter().zip(&frames) {
+            let expected = envelope::decode(f).unwrap();
+            assert_eq!(env.method, expected.method);
+            assert_eq!(env.payload, expected.payload);
+            assert_eq!(env.aux, expected.aux);
+            assert_eq!(env.tag, expected.tag);
+        }
+        assert_eq!(dec.buffered(), 0);
+    }
+}
+
+#[test]
+fn stream_decoder_reports_exact_need() {
+    let frame = &sample_frames()[2];
+    // payload (700 bytes) is followed by the 3-byte aux field
+    let payload_end = frame.len() - tleb3::encode_len(3).len() - 3;
+    let payload_start = payload_end - 700;
+    let mut dec = StreamDecoder::new();
+    assert_eq!(dec.next_frame().unwrap(), Progress::NeedMore(1));
+    dec.feed(&frame[..payload_start + 5]);
+    assert_eq!(dec.next_frame().unwrap(), Progress::NeedMore(695));
+    dec.feed(&frame[payload_start + 5..]);
+    assert!(matches!(dec.next_frame().unwrap(), Progress::Complete(_)));
+    assert_eq!(dec.buffered(), 0);
+}
+
+#[test]
+fn stream_decoder_limits_frame_size() {
+    let frame = &sample_frames()[2];
+    let payload_end = frame.len() - tleb3::encode_len(3).len() - 3;
+    let mut dec = StreamDecoder::with_max_frame_len(512);
+    dec.feed(frame);
+    assert_eq!(
+        dec.next_frame(),
+        Err(Error::FrameTooLarge {
+            len: payload_end as u64,
+            max: 512
+        })
+    );
+}
+
+#[test]
+fn stream_decoder_completes_frames_from_their_flags() {
+    // Ping has no AUX and its flags say so: no lookahead into the next frame
+    let frames = sample_frames();
+    let mut dec = StreamDecoder::new();
+    dec.feed(&frames[1]);
+    assert!(matches!(dec.next_frame().unwrap(), Progress::Complete(_)));
+    assert_eq!(dec.buffered(), 0);
+}
+
+#[test]
+fn stream_decoder_reads_reference_frames_back_to_back() {
+    let path = format!(
+        "{}/../../fixtures/vectors_hex.txt",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let frames: Vec<Vec<u8>> = fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| hex::decode(l.split_once(' ').unwrap().1).unwrap())
+        .collect();
+    let wire = frames.concat();
+    let mut dec = StreamDecoder::new();
+    let mut out = Vec::new();
+    for piece in wire.chunks(5) {
+        dec.feed(piece);
+        while let Progress::Complete(env) = dec.next_frame().unwrap() {
+            out.push(env);
+        }
+    }
+    assert_eq!(out.len(), frames.len());
+    for (env, f) in out.iter().zip(&frames) {
+        assert_eq!(env, &envelope::decode(f).unwrap());
+    }
+}
+
+#[test]
+fn stream_decoder_errors_are_sticky() {
+    let frames = sample_frames();
+    let mut dec = StreamDecoder::new();
+    // well-formed first field, wrong magic
+    let mut bad = tleb3::encode_len(2);
+    bad.extend([0xF3, 0x2B]);
+    dec.feed(&bad);
+    dec.feed(&frames[1]);
+    assert_eq!(dec.next_frame(), Err(Error::BadMagic { offset: 0 }));
+    assert_eq!(dec.next_frame(), Err(Error::BadMagic { offset: 0 }));
+
+    let mut dec = StreamDecoder::new();
+    dec.feed(&[0xFF]);
+    let err = dec.next_frame().unwrap_err();
+    dec.feed(&frames[1]);
+    assert_eq!(dec.next_frame(), Err(err));
+}
+
+#[test]
+fn stream_nonce_counts_from_base() {
+    let mut base = [2u8; 24];
+    base[20..].copy_from_slice(&[0, 0, 0, 0xFE]);
+    let mut sn = envelope::StreamNonce::new(base);
+    assert_eq!(&sn.next_nonce().unwrap()[20..], &[0, 0, 0, 0xFE]);
+    assert_eq!(&sn.next_nonce().unwrap()[20..], &[0, 0, 0, 0xFF]);
+    assert_eq!(&sn.next_nonce().unwrap()[20..], &[0, 0, 1, 0x00]);
+    assert_eq!(sn.chunk_index(), 3);
+    assert_eq!(&sn.derive(0).unwrap()[..20], &[2u8; 20]);
+
+    base[20..].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFE]);
+    let mut sn = envelope::StreamNonce::new(base);
+    assert!(sn.next_nonce().is_ok());
+    assert!(sn.next_nonce().is_ok());
+    assert_eq!(
+        sn.next_nonce(),
+        Err(Error::NonceExhausted { chunk_index: 2 })
+    );
+    assert_eq!(sn.chunk_index(), 2);
+}
+
+#[test]
+fn stream_nonce_seals_and_opens_in_order() {
+    let key = [4u8; 32];
+    let base = [6u8; 24];
+    let mut tx = envelope::StreamNonce::new(base);
+    let mut rx = envelope::StreamNonce::new(base);
+    let mut frames = Vec::new();
+    for (method, payload) in [("S.OPEN", &b"req"[..]), ("S.DATA", b"d1"), ("S.CLOSE", b"")] {
+        let mut out = Vec::new();
+        let b = envelope::EnvelopeBuilder::new("hyper.v1", method).payload(payload);
+        tx.seal(&b, &key, &mut out).unwrap();
+        frames.push(out);
+    }
+    // out of order: DATA does not verify under the OPEN nonce, and the counter stays put
+    assert_eq!(rx.open(&frames[1], &key), Err(Error::AuthFailed));
+    assert_eq!(rx.chunk_index(), 0);
+    for f in &frames {
+        rx.open(f, &key).unwrap();
+    }
+    assert_eq!(rx.chunk_index(), 3);
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use tritrpc_v1::aux::{self, AuxBundle};
+use tritrpc_v1::avrodec::Trace;
+use tritrpc_v1::envelope::{self, EnvelopeBuilder};
+use tritrpc_v1::tracecontext::{self, TraceParent, TraceState};
+use tritrpc_v1::Error;
+
+const HEADER: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+#[test]
+fn traceparent_round_trips_through_trace() {
+    let tp = TraceParent::parse(HEADER).unwrap();
+    assert!(tp.sampled());
+    let trace = tp.to_trace();
+    assert_eq!(trace.span_id, "00f067aa0ba902b7");
+    assert_eq!(
+        TraceParent::from_trace(&trace, 0x01).unwrap().to_string(),
+        HEADER
+    );
+    assert_eq!(
+        TraceParent::from_trace(&trace, 0x00).unwrap().to_string(),
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+    );
+    // future versions may append fields
+    let v1 = format!("01{}-extra", &HEADER[2..]);
+    assert_eq!(TraceParent::parse(&v1).unwrap(), tp);
+}
+
+#[test]
+fn traceparent_validation() {
+    for bad in [
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-+1",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0A",
+        "+0-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "0A-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "",
+    ] {
+        assert!(
+            matches!(TraceParent::parse(bad), Err(Error::BadTraceContext { .. })),
+            "{}",
+            bad
+        );
+    }
+    // fixture-style ids are not W3C ids
+    let short = Trace {
+        trace_id: "t-01".into(),
+        span_id: "s-01".into(),
+        parent_span_id: None,
+    };
+    assert_eq!(
+        TraceParent::from_trace(&short, 1),
+        Err(Error::BadTraceContext { what: "trace-id" })
+    );
+}
+
+#[test]
+fn tracestate_parse_and_format() {
+    let ts = TraceState::parse("rojo=00f067aa0ba902b7, congo=t61rcWkgMzE,,t@sys=x").unwrap();
+    assert_eq!(ts.members.len(), 3);
+    assert_eq!(
+        ts.to_string(),
+        "rojo=00f067aa0ba902b7,congo=t61rcWkgMzE,t@sys=x"
+    );
+    assert!(TraceState::parse("").unwrap().is_empty());
+    for bad in ["Rojo=1", "rojo", "rojo=a,rojo=b", "rojo=a=b", "rojo="] {
+        assert!(TraceState::parse(bad).is_err(), "{}", bad);
+    }
+    let many: Vec<String> = (0..33).map(|i| format!("k{}=v", i)).collect();
+    assert!(TraceState::parse(&many.join(",")).is_err());
+}
+
+#[test]
+fn server_span_inherits_frame_trace() {
+    let tp = TraceParent::parse(HEADER).unwrap();
+    let ts = TraceState::parse("rojo=00f067aa0ba902b7").unwrap();
+    let mut bundle = AuxBundle::new();
+    tracecontext::inject(&mut bundle, &tp, Some(&ts));
+    let aux = bundle.encode();
+    let frame = EnvelopeBuilder::new("hyper.v1", "Ping").aux(&aux).to_vec();
+    let env = envelope::decode(&frame).unwrap();
+
+    assert_eq!(
+        tracecontext::extract(&env, 0x01).unwrap(),
+        Some((tp.clone(), ts))
+    );
+    let span = tracecontext::server_span(&env, "b7ad6b7169203331")
+        .unwrap()
+        .unwrap();
+    assert_eq!(span.trace_id, tp.trace_id);
+    assert_eq!(span.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+    assert_eq!(span.span_id, "b7ad6b7169203331");
+
+    // no Trace AUX: the server starts its own root span
+    let bare = envelope::decode(&EnvelopeBuilder::new("hyper.v1", "Ping").to_vec()).unwrap();
+    assert_eq!(
+        tracecontext::server_span(&bare, "b7ad6b7169203331"),
+        Ok(None)
+    );
+    assert_eq!(tracecontext::extract(&bare, 0x01), Ok(None));
+
+    // the trace-only helper round-trips too
+    let only = aux::trace_aux(&span);
+    let frame = EnvelopeBuilder::new("hyper.v1", "Ping").aux(&only).to_vec();
+    let (parent, state) = tracecontext::extract(&envelope::decode(&frame).unwrap(), 0)
+        .unwrap()
+        .unwrap();
+    assert_eq!(parent.parent_id, "b7ad6b7169203331");
+    assert!(state.is_empty());
+}
+
+#[test]
+fn server_span_continues_reference_stream_frames() {
+    // The Python reference's stream frames carry a bare Trace as AUX with
+    // fixture-style ids.
+    let path = format!(
+        "{}/../../fixtures/vectors_hex.txt",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let txt = fs::read_to_string(path).unwrap();
+    let mut seen = 0;
+    for line in txt
+        .lines()
+        .filter(|l| l.contains("Stream.OPEN") || l.contains("Stream.DATA"))
+    {
+        let (name, hexs) = line.split_once(' ').unwrap();
+        let env = envelope::decode(&hex::decode(hexs).unwrap()).unwrap();
+        let incoming = env.trace().unwrap().unwrap();
+        let span = tracecontext::server_span(&env, "b7ad6b7169203331")
+            .unwrap()
+            .expect(name);
+        assert_eq!(span.trace_id, "t-01", "{}", name);
+        assert_eq!(span.parent_span_id, Some(incoming.span_id), "{}", name);
+        assert_eq!(span.span_id, "b7ad6b7169203331");
+        // not W3C ids, so there is no traceparent for them
+        assert_eq!(
+            tracecontext::extract(&env, 0),
+            Err(Error::BadTraceContext { what: "trace-id" })
+        );
+        seen += 1;
+    }
+    assert_eq!(seen, 3);
+}
+
+#[test]
+fn extract_reads_bare_trace_aux() {
+    let tp = TraceParent::parse(HEADER).unwrap();
+    let bare = tritrpc_v1::avrodec::enc_trace(&tp.to_trace());
+    let frame = EnvelopeBuilder::new("hyper.v1", "S.OPEN")
+        .aux(&bare)
+        .to_vec();
+    let env = envelope::decode(&frame).unwrap();
+    assert_eq!(
+        tracecontext::extract(&env, 0x01),
+        Ok(Some((tp, TraceState::default())))
+    );
+    // the reference's CLOSE frame carries a bare PoE and no Trace
+    let path = format!(
+        "{}/../../fixtures/vectors_hex.txt",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let txt = fs::read_to_string(path).unwrap();
+    let close = txt.lines().find(|l| l.contains("Stream.CLOSE")).unwrap();
+    let frame = hex::decode(close.split_once(' ').unwrap().1).unwrap();
+    let env = envelope::decode(&frame).unwrap();
+    assert_eq!(tracecontext::extract(&env, 0x01), Ok(None));
+    assert_eq!(
+        tracecontext::server_span(&env, "b7ad6b7169203331"),
+        Ok(None)
+    );
+}
@@ -1,4 +1,5 @@
-use tritrpc_v1::{tleb3, tritpack243};
+use tritrpc_v1::tritpack243::{Trit, TritVec, UnpackMode};
+use tritrpc_v1::{tleb3, tritpack243, Error};
 
 #[test]
 fn micro_vectors() {
@@ -16,3 +17,123 @@ fn tleb3_roundtrip() {
         assert_eq!(dec, n);
     }
 }
+
+#[test]
+fn try_pack_rejects_invalid_trits() {
+    assert_eq!(tritpack243::try_pack(&[2, 1, 0, 0, 2]).unwrap(), vec![0xBF]);
+    assert_eq!(
+        tritpack243::try_pack(&[0, 1, 2, 3]),
+        Err(Error::InvalidTrit { value: 3, index: 3 })
+    );
+    assert!(Trit::new(3).is_none());
+    assert_eq!(
+        Trit::try_from(7u8),
+        Err(Error::InvalidTritValue { value: 7 })
+    );
+}
+
+#[test]
+fn trit_vec_roundtrip() {
+    let tv = TritVec::from_raw(&[2, 2, 1]).unwrap();
+    assert_eq!(tv.len(), 3);
+    assert_eq!(tv[2], Trit::ONE);
+    assert_eq!(tv.pack(), vec![0xF5, 0x19]);
+    assert_eq!(TritVec::unpack(&tv.pack()).unwrap(), tv);
+    let built: TritVec = [Trit::TWO, Trit::ZERO].into_iter().collect();
+    assert_eq!(built.to_raw(), vec![2, 0]);
+}
+
+#[test]
+fn strict_unpack_rejects_non_canonical() {
+    // tail marker followed by a full group
+    let mid_tail = [0xF3, 0x01, 0x00];
+    assert_eq!(
+        tritpack243::unpack(&mid_tail),
+        Err(Error::TailNotFinal { offset: 0 })
+    );
+    // one-trit tail may only carry 0..=2
+    let oversized = [0x00, 0xF3, 0xFF];
+    assert_eq!(
+        tritpack243::unpack(&oversized),
+        Err(Error::TailValueOutOfRange {
+            value: 0xFF,
+            trits: 1,
+            offset: 2
+        })
+    );
+    assert_eq!(
+        tritpack243::unpack_lenient(&mid_tail).unwrap(),
+        vec![1, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        tritpack243::unpack_with(&oversized, UnpackMode::Lenient).unwrap(),
+        vec![0, 0, 0, 0, 0, 0]
+    );
+    // largest canonical 4-trit tail is 80
+    assert_eq!(tritpack243::unpack(&[0xF6, 80]).unwrap(), vec![2, 2, 2, 2]);
+    assert!(tritpack243::unpack(&[0xF6, 81]).is_err());
+}
+
+#[test]
+fn unpack_group_past_end_is_eof() {
+    assert_eq!(
+        tritpack243::unpack_group(&[], 0, UnpackMode::Strict),
+        Err(Error::Eof {
+            what: "TritPack243 group",
+            offset: 0
+        })
+    );
+    assert!(tritpack243::unpack_group(&[0xBF], 1, UnpackMode::Lenient).is_err());
+}
+
+#[test]
+fn tleb3_extremes_roundtrip() {
+    for &n in [9u64.pow(20), u64::MAX - 1, u64::MAX].iter() {
+        let enc = tleb3::encode_len(n);
+        assert_eq!(tleb3::decode_len(&enc, 0).unwrap(), (n, enc.len()));
+    }
+}
+
+#[test]
+fn tleb3_rejects_non_canonical() {
+    // 5 as [5, 0]: a redundant high zero digit
+    let non_minimal = tritpack243::pack(&[2, 1, 2, 0, 0, 0]);
+    assert!(matches!(
+        tleb3::decode_len(&non_minimal, 0),
+        Err(Error::NonCanonicalTleb { offset: 0, .. })
+    ));
+    // final tritlet followed by extra trits in the same full group
+    for extra in [[0u8, 0], [1, 2]] {
+        let padded = tritpack243::pack(&[0, 1, 2, extra[0], extra[1]]);
+        assert!(matches!(
+            tleb3::decode_len(&padded, 0),
+            Err(Error::NonCanonicalTleb { .. })
+        ));
+    }
+    // continuation trit 1 is not a valid marker
+    let bad_c = tritpack243::pack(&[1, 0, 1]);
+    assert!(matches!(
+        tleb3::decode_len(&bad_c, 0),
+        Err(Error::NonCanonicalTleb { .. })
+    ));
+}
+
+#[test]
+fn tleb3_rejects_overflow() {
+    // 21 digits of 8: larger than u64::MAX
+    let mut trits = Vec::new();
+    for i in 0..21 {
+        trits.extend_from_slice(&[if i < 20 { 2 } else { 0 }, 2, 2]);
+    }
+    let enc = tritpack243::pack(&trits);
+    assert_eq!(
+        tleb3::decode_len(&enc, 0),
+        Err(Error::TlebOverflow { offset: 0 })
+    );
+    // endless continuation is cut off at MAX_DIGITS
+    let endless = tritpack243::pack(&[2u8; 66]);
+    assert_eq!(
+        tleb3::decode_len(&endless, 0),
+        Err(Error::TlebOverflow { offset: 0 })
+    );
+}
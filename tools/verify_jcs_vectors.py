@@ -0,0 +1,149 @@
+#!/usr/bin/env python3
+# Verifies fixtures/jcs_blake3_vectors.json independently of the Rust crate:
+#   canonical_json == RFC 8785 (JCS) canonicalization of json_input, and
+#   blake3_hex     == BLAKE3-256 of the UTF-8 bytes of canonical_json.
+# BLAKE3 is implemented inline (after the BLAKE3 reference implementation) so the
+# check needs nothing beyond the standard library. Exits non-zero on the first mismatch.
+import json
+import math
+import sys
+from pathlib import Path
+
+ROOT = Path(__file__).resolve().parents[1]
+VECTORS = ROOT / "fixtures" / "jcs_blake3_vectors.json"
+
+# --- BLAKE3 (hash mode, 32-byte output) ---
+
+MASK = 0xFFFFFFFF
+IV = [0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A,
+      0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19]
+MSG_PERMUTATION = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8]
+CHUNK_LEN = 1024
+BLOCK_LEN = 64
+CHUNK_START, CHUNK_END, PARENT, ROOT_FLAG = 1, 2, 4, 8
+
+def rotr(x: int, n: int) -> int:
+    return ((x >> n) | (x << (32 - n))) & MASK
+
+def g(s, a, b, c, d, mx, my):
+    s[a] = (s[a] + s[b] + mx) & MASK
+    s[d] = rotr(s[d] ^ s[a], 16)
+    s[c] = (s[c] + s[d]) & MASK
+    s[b] = rotr(s[b] ^ s[c], 12)
+    s[a] = (s[a] + s[b] + my) & MASK
+    s[d] = rotr(s[d] ^ s[a], 8)
+    s[c] = (s[c] + s[d]) & MASK
+    s[b] = rotr(s[b] ^ s[c], 7)
+
+def round_fn(s, m):
+    g(s, 0, 4, 8, 12, m[0], m[1])
+    g(s, 1, 5, 9, 13, m[2], m[3])
+    g(s, 2, 6, 10, 14, m[4], m[5])
+    g(s, 3, 7, 11, 15, m[6], m[7])
+    g(s, 0, 5, 10, 15, m[8], m[9])
+    g(s, 1, 6, 11, 12, m[10], m[11])
+    g(s, 2, 7, 8, 13, m[12], m[13])
+    g(s, 3, 4, 9, 14, m[14], m[15])
+
+def compress(cv, block: bytes, counter: int, block_len: int, flags: int):
+    m = [int.from_bytes(block[4 * i:4 * i + 4], "little") for i in range(16)]
+    s = list(cv) + IV[:4] + [counter & MASK, (counter >> 32) & MASK, block_len, flags]
+    for r in range(7):
+        round_fn(s, m)
+        if r < 6:
+            m = [m[i] for i in MSG_PERMUTATION]
+    for i in range(8):
+        s[i] ^= s[i + 8]
+        s[i + 8] ^= cv[i]
+    return s
+
+def chunk_cv(chunk: bytes, counter: int, root: bool):
+    cv = IV
+    blocks = [chunk[i:i + BLOCK_LEN] for i in range(0, len(chunk), BLOCK_LEN)] or [b""]
+    for i, blk in enumerate(blocks):
+        flags = (CHUNK_START if i == 0 else 0) | (CHUNK_END if i == len(blocks) - 1 else 0)
+        if root and i == len(blocks) - 1:
+            flags |= ROOT_FLAG
+        cv = compress(cv, blk.ljust(BLOCK_LEN, b"\0"), counter, len(blk), flags)[:8]
+    return cv
+
+def subtree_cv(data: bytes, first_chunk: int, root: bool):
+    if len(data) <= CHUNK_LEN:
+        return chunk_cv(data, first_chunk, root)
+    # The left subtree holds the largest power-of-two number of whole chunks.
+    chunks = (len(data) + CHUNK_LEN - 1) // CHUNK_LEN
+    left_chunks = 1 << ((chunks - 1).bit_length() - 1)
+    split = left_chunks * CHUNK_LEN
+    left = subtree_cv(data[:split], first_chunk, False)
+    right = subtree_cv(data[split:], first_chunk + left_chunks, False)
+    block = b"".join(w.to_bytes(4, "little") for w in left + right)
+    return compress(IV, block, 0, BLOCK_LEN, PARENT | (ROOT_FLAG if root else 0))[:8]
+
+def blake3_hex(data: bytes) -> str:
+    return b"".join(w.to_bytes(4, "little") for w in subtree_cv(data, 0, True)).hex()
+
+# --- RFC 8785 (JCS) ---
+
+def jcs_number(x) -> str:
+    if isinstance(x, int) and abs(x) < 2**53:
+        return str(x)
+    x = float(x)
+    if not math.isfinite(x):
+        raise ValueError("non-finite number")
+    if x == 0:
+        return "0"
+    # repr gives the shortest round-trip digits; lay them out per ECMAScript Number::toString.
+    m, _, e = repr(abs(x)).lower().partition("e")
+    ip, _, fp = m.partition(".")
+    digits = ip + fp
+    n = len(ip) + int(e or 0) - (len(digits) - len(digits.lstrip("0")))
+    ds = digits.strip("0")
+    k = len(ds)
+    if k <= n <= 21:
+        s = ds + "0" * (n - k)
+    elif 0 < n <= 21:
+        s = ds[:n] + "." + ds[n:]
+    elif -6 < n <= 0:
+        s = "0." + "0" * (-n) + ds
+    else:
+        frac = "." + ds[1:] if k > 1 else ""
+        s = ds[0] + frac + "e" + ("+" if n > 0 else "-") + str(abs(n - 1))
+    return ("-" if x < 0 else "") + s
+
+def jcs(v) -> str:
+    if v is None:
+        return "null"
+    if v is True:
+        return "true"
+    if v is False:
+        return "false"
+    if isinstance(v, (int, float)):
+        return jcs_number(v)
+    if isinstance(v, str):
+        return json.dumps(v, ensure_ascii=False)
+    if isinstance(v, list):
+        return "[" + ",".join(jcs(x) for x in v) + "]"
+    if isinstance(v, dict):
+        keys = sorted(v, key=lambda k: k.encode("utf-16-be"))
+        return "{" + ",".join(json.dumps(k, ensure_ascii=False) + ":" + jcs(v[k]) for k in keys) + "}"
+    raise TypeError(f"unsupported JSON value {v!r}")
+
+def main() -> int:
+    # Published BLAKE3 test values guard the inline implementation itself.
+    assert blake3_hex(b"") == "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    assert blake3_hex(b"abc") == "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    vectors = json.loads(VECTORS.read_text())
+    for vec in vectors:
+        canon = jcs(json.loads(vec["json_input"]))
+        if canon != vec["canonical_json"]:
+            print(f"{vec['name']}: canonical_json {vec['canonical_json']!r} != JCS {canon!r}", file=sys.stderr)
+            return 1
+        digest = blake3_hex(canon.encode("utf-8"))
+        if digest != vec["blake3_hex"]:
+            print(f"{vec['name']}: blake3_hex {vec['blake3_hex']} != BLAKE3 {digest}", file=sys.stderr)
+            return 1
+    print(f"OK: {len(vectors)} JCS/BLAKE3 vectors verified")
+    return 0
+
+if __name__ == "__main__":
+    sys.exit(main())